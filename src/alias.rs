@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use super::peg::{parse, Pipeline, Job};
+use super::diagnostics::error;
+use super::quoting::escape;
+use super::status::{SUCCESS, FAILURE};
+
+/// An alias, which replaces the name of a command with one or more words.
+struct Alias {
+    /// The code it was defined with, which is printed by `alias`
+    value: String,
+    /// The unexpanded words of the code, which are expanded along with the rest of the command
+    words: Vec<String>,
+}
+
+/// The aliases defined with `alias`.
+pub struct Aliases {
+    aliases: BTreeMap<String, Alias>,
+}
+
+impl Default for Aliases {
+    fn default() -> Aliases {
+        Aliases { aliases: BTreeMap::new() }
+    }
+}
+
+impl Aliases {
+    /// Whether the word can be the name of an alias, which is only matched when it is unquoted.
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || "=/'\"\\$|&;<>()".contains(c))
+    }
+
+    /// Parses the value of an alias, which has to be a single command without redirections.
+    fn words(value: &str) -> Result<Vec<String>, String> {
+        let mut pipelines = try!(parse(value).map_err(|err| err.to_string()));
+        if pipelines.len() != 1 {
+            return Err("the value is not a single command".to_string());
+        }
+        let pipeline = pipelines.remove(0);
+        if pipeline.jobs.len() != 1 || !pipeline.redirections.is_empty() || !pipeline.chain.is_empty() ||
+           pipeline.is_background() {
+            return Err("the value is not a single command".to_string());
+        }
+        Ok(pipeline.jobs[0].args.clone())
+    }
+
+    fn print(&self, name: &str) {
+        if let Some(alias) = self.aliases.get(name) {
+            println!("alias {}={}", name, escape(&alias.value));
+        }
+    }
+
+    /// Defines aliases with `alias name=value...`, or prints them in a form that can be added to
+    /// `~/.ionrc` to define them again in every new shell.
+    pub fn alias<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let args: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+        if args.is_empty() {
+            for name in self.aliases.keys() {
+                self.print(name);
+            }
+            return SUCCESS;
+        }
+        let mut status = SUCCESS;
+        for arg in &args {
+            let (name, value) = match arg.find('=') {
+                Some(equals) => (&arg[..equals], &arg[equals+1..]),
+                None => {
+                    if self.aliases.contains_key(arg.as_str()) {
+                        self.print(arg);
+                    } else {
+                        error("alias", format!("not found: {}", arg));
+                        status = FAILURE;
+                    }
+                    continue;
+                }
+            };
+            if !Aliases::is_valid_name(name) {
+                error("alias", format!("invalid alias name: {}", name));
+                status = FAILURE;
+                continue;
+            }
+            match Aliases::words(value) {
+                Ok(words) => {
+                    self.aliases.insert(name.to_string(), Alias { value: value.to_string(), words: words });
+                },
+                Err(message) => {
+                    error("alias", format!("{}: {}", name, message));
+                    status = FAILURE;
+                }
+            }
+        }
+        status
+    }
+
+    /// Removes the aliases with the given names, or all of them with `-a`.
+    pub fn unalias<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let args: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+        if args.is_empty() {
+            error("unalias", "missing alias name");
+            return FAILURE;
+        }
+        if args[0] == "-a" {
+            self.aliases.clear();
+            return SUCCESS;
+        }
+        let mut status = SUCCESS;
+        for name in &args {
+            if self.aliases.remove(name.as_str()).is_none() {
+                error("unalias", format!("not found: {}", name));
+                status = FAILURE;
+            }
+        }
+        status
+    }
+
+    /// Replaces the name of each command of the unexpanded pipeline that is an alias with the
+    /// words of the alias. The first word of an alias may be an alias in turn, but not one that
+    /// was already replaced, so that `alias ls='ls -F'` runs the command `ls`.
+    pub fn expand(&self, pipeline: &Pipeline) -> Pipeline {
+        let mut pipeline = pipeline.clone();
+        for job in &mut pipeline.jobs {
+            let mut replaced: Vec<String> = vec![];
+            let mut args = job.args.clone();
+            while let Some(alias) = self.aliases.get(&args[0]) {
+                if replaced.contains(&args[0]) {
+                    break;
+                }
+                replaced.push(args[0].clone());
+                args = alias.words.iter().chain(args[1..].iter()).cloned().collect();
+            }
+            if !replaced.is_empty() {
+                *job = Job::new(args, job.background);
+            }
+        }
+        pipeline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status::{SUCCESS, FAILURE};
+
+    fn expand(aliases: &Aliases, code: &str) -> Vec<Vec<String>> {
+        let pipeline = parse(code).unwrap().remove(0);
+        aliases.expand(&pipeline).jobs.iter().map(|job| job.args.clone()).collect()
+    }
+
+    #[test]
+    fn define_and_remove_aliases() {
+        let mut aliases = Aliases::default();
+        assert_eq!(SUCCESS, aliases.alias(vec!["alias", "ll=ls -l \"$HOME\""]));
+        assert_eq!(vec![vec!["ls", "-l", "\"$HOME\"", "src"]], expand(&aliases, "ll src"));
+        assert_eq!(FAILURE, aliases.alias(vec!["alias", "bad=ls | wc"]));
+        assert_eq!(FAILURE, aliases.alias(vec!["alias", "a/b=ls"]));
+        assert_eq!(FAILURE, aliases.alias(vec!["alias", "missing"]));
+        assert_eq!(SUCCESS, aliases.unalias(vec!["unalias", "ll"]));
+        assert_eq!(FAILURE, aliases.unalias(vec!["unalias", "ll"]));
+        assert_eq!(vec![vec!["ll", "src"]], expand(&aliases, "ll src"));
+    }
+
+    #[test]
+    fn recursive_aliases() {
+        let mut aliases = Aliases::default();
+        aliases.alias(vec!["alias", "ls=ls -F", "l=ls -a", "a=b", "b=a x"]);
+        assert_eq!(vec![vec!["ls", "-F", "-a", "src"], vec!["wc"]], expand(&aliases, "l src | wc"));
+        assert_eq!(vec![vec!["a", "x", "y"]], expand(&aliases, "a y"));
+        assert_eq!(vec![vec!["'ls'"]], expand(&aliases, "'ls'"));
+    }
+}
@@ -0,0 +1,429 @@
+//! Evaluation of the arithmetic expressions of `$((...))` and the `math` builtin
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use super::variables::Variables;
+use super::diagnostics::error;
+use super::status::{SUCCESS, FAILURE};
+
+/// The value of an arithmetic expression. Operations on two integers stay integers, any float
+/// operand makes the result a float.
+#[derive(Clone, Copy, Debug)]
+pub enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Number {
+    /// Parses a decimal or `0x` hexadecimal integer, or a float like `1.5` or `2e-3`.
+    pub fn parse(text: &str) -> Result<Number, String> {
+        let text = text.trim();
+        let (negative, digits) = if text.starts_with('-') {
+            (true, &text[1..])
+        } else if text.starts_with('+') {
+            (false, &text[1..])
+        } else {
+            (false, text)
+        };
+        if digits.starts_with("0x") || digits.starts_with("0X") {
+            let hex = &digits[2..];
+            if hex.is_empty() || !hex.chars().all(|c| c.is_digit(16)) {
+                return Err(format!("invalid number: {}", text));
+            }
+            let value = try!(i64::from_str_radix(hex, 16).map_err(|_| format!("number too large: {}", text)));
+            return Ok(Number::Integer(if negative { -value } else { value }));
+        }
+        if digits.is_empty() || !digits.chars().any(|c| c.is_digit(10)) {
+            return Err(format!("invalid number: {}", text));
+        }
+        if digits.chars().all(|c| c.is_digit(10)) {
+            // Parsing with the sign keeps the most negative integer representable
+            let signed = if negative { format!("-{}", digits) } else { digits.to_string() };
+            return signed.parse::<i64>().map(Number::Integer).map_err(|_| format!("number too large: {}", text));
+        }
+        if !digits.chars().all(|c| c.is_digit(10) || ".eE+-".contains(c)) {
+            return Err(format!("invalid number: {}", text));
+        }
+        match digits.parse::<f64>() {
+            Ok(value) if value.is_finite() => Ok(Number::Float(if negative { -value } else { value })),
+            Ok(_) => Err(format!("number too large: {}", text)),
+            Err(_) => Err(format!("invalid number: {}", text)),
+        }
+    }
+
+    fn as_float(&self) -> f64 {
+        match *self {
+            Number::Integer(value) => value as f64,
+            Number::Float(value) => value,
+        }
+    }
+
+    fn is_true(&self) -> bool {
+        self.as_float() != 0.0
+    }
+
+    fn integer(&self, operator: &str) -> Result<i64, String> {
+        match *self {
+            Number::Integer(value) => Ok(value),
+            Number::Float(_) => Err(format!("operator {} requires integer operands", operator)),
+        }
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Number) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Number) -> Option<Ordering> {
+        match (*self, *other) {
+            (Number::Integer(left), Number::Integer(right)) => Some(left.cmp(&right)),
+            (left, right) => left.as_float().partial_cmp(&right.as_float()),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Number::Integer(value) => write!(f, "{}", value),
+            Number::Float(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Number(Number),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+/// Operators made of two characters come first, so that `<=` is not read as `<` followed by `=`.
+const OPERATORS: [&'static str; 21] = ["**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+                                        "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "~", "!"];
+
+/// Splits the expression into tokens, replacing variable names by their values.
+fn tokenize<F>(expression: &str, lookup: &F) -> Result<Vec<Token>, String>
+    where F: Fn(&str) -> Option<String>
+{
+    let mut tokens = vec![];
+    let mut index = 0;
+    while index < expression.len() {
+        let rest = &expression[index..];
+        let character = rest.chars().next().unwrap();
+        if character.is_whitespace() {
+            index += character.len_utf8();
+        } else if character == '(' {
+            tokens.push(Token::Open);
+            index += 1;
+        } else if character == ')' {
+            tokens.push(Token::Close);
+            index += 1;
+        } else if character.is_digit(10) || (character == '.' && rest[1..].starts_with(|c: char| c.is_digit(10))) {
+            let is_hex = rest.starts_with("0x") || rest.starts_with("0X");
+            let mut end = 0;
+            let mut previous = ' ';
+            for (offset, c) in rest.char_indices() {
+                let exponent_sign = (c == '+' || c == '-') && (previous == 'e' || previous == 'E') && !is_hex;
+                if !(c.is_alphanumeric() || c == '.' || exponent_sign) {
+                    break;
+                }
+                end = offset + c.len_utf8();
+                previous = c;
+            }
+            tokens.push(Token::Number(try!(Number::parse(&rest[..end]))));
+            index += end;
+        } else if character.is_alphabetic() || character == '_' {
+            let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let name = &rest[..end];
+            let value = try!(lookup(name).ok_or(format!("{}: undefined variable", name)));
+            let number = try!(Number::parse(&value).map_err(|_| format!("{}: not a number: {}", name, value)));
+            tokens.push(Token::Number(number));
+            index += end;
+        } else {
+            match OPERATORS.iter().find(|operator| rest.starts_with(*operator)) {
+                Some(operator) => {
+                    tokens.push(Token::Operator(operator));
+                    index += operator.len();
+                },
+                None => return Err(format!("unexpected character: {}", character)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// The precedence of a binary operator, higher binding tighter, and whether it is right
+/// associative.
+fn precedence(operator: &str) -> Option<(u8, bool)> {
+    match operator {
+        "||" => Some((1, false)),
+        "&&" => Some((2, false)),
+        "|" => Some((3, false)),
+        "^" => Some((4, false)),
+        "&" => Some((5, false)),
+        "==" | "!=" => Some((6, false)),
+        "<" | "<=" | ">" | ">=" => Some((7, false)),
+        "<<" | ">>" => Some((8, false)),
+        "+" | "-" => Some((9, false)),
+        "*" | "/" | "%" => Some((10, false)),
+        "**" => Some((11, true)),
+        _ => None,
+    }
+}
+
+fn boolean(value: bool) -> Number {
+    Number::Integer(if value { 1 } else { 0 })
+}
+
+fn overflow<T>(result: Option<T>) -> Result<T, String> {
+    result.ok_or("integer overflow".to_string())
+}
+
+fn power(base: i64, exponent: i64) -> Result<i64, String> {
+    let (mut base, mut exponent, mut result) = (base, exponent, 1i64);
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = try!(overflow(result.checked_mul(base)));
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = try!(overflow(base.checked_mul(base)));
+        }
+    }
+    Ok(result)
+}
+
+fn shift(value: i64, amount: i64, operator: &str) -> Result<i64, String> {
+    if amount < 0 || amount > 63 {
+        return Err(format!("invalid shift amount: {}", amount));
+    }
+    Ok(if operator == "<<" { value << amount } else { value >> amount })
+}
+
+fn apply(operator: &str, left: Number, right: Number) -> Result<Number, String> {
+    match operator {
+        "||" => return Ok(boolean(left.is_true() || right.is_true())),
+        "&&" => return Ok(boolean(left.is_true() && right.is_true())),
+        "==" => return Ok(boolean(left == right)),
+        "!=" => return Ok(boolean(left != right)),
+        "<" => return Ok(boolean(left < right)),
+        "<=" => return Ok(boolean(left <= right)),
+        ">" => return Ok(boolean(left > right)),
+        ">=" => return Ok(boolean(left >= right)),
+        "|" | "^" | "&" | "<<" | ">>" => {
+            let (left, right) = (try!(left.integer(operator)), try!(right.integer(operator)));
+            return Ok(Number::Integer(match operator {
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                _ => try!(shift(left, right, operator)),
+            }));
+        },
+        _ => (),
+    }
+    if (operator == "/" || operator == "%") && !right.is_true() {
+        return Err("division by zero".to_string());
+    }
+    match (left, right) {
+        (Number::Integer(_), Number::Integer(right)) if operator == "**" && right < 0 => {
+            Ok(Number::Float(left.as_float().powf(right as f64)))
+        },
+        (Number::Integer(left), Number::Integer(right)) => {
+            Ok(Number::Integer(try!(match operator {
+                "+" => overflow(left.checked_add(right)),
+                "-" => overflow(left.checked_sub(right)),
+                "*" => overflow(left.checked_mul(right)),
+                "/" => overflow(left.checked_div(right)),
+                "%" => overflow(left.checked_rem(right)),
+                _ => power(left, right),
+            })))
+        },
+        (left, right) => {
+            let (left, right) = (left.as_float(), right.as_float());
+            let result = match operator {
+                "+" => left + right,
+                "-" => left - right,
+                "*" => left * right,
+                "/" => left / right,
+                "%" => left % right,
+                _ => left.powf(right),
+            };
+            if result.is_finite() { Ok(Number::Float(result)) } else { Err("float overflow".to_string()) }
+        },
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<&Token> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    /// Parses the binary operations whose operators bind at least as tight as `minimum`.
+    fn expression(&mut self, minimum: u8) -> Result<Number, String> {
+        let mut left = try!(self.unary());
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::Operator(operator)) => operator,
+                _ => break,
+            };
+            let (level, right_associative) = match precedence(operator) {
+                Some(precedence) => precedence,
+                None => return Err(format!("unexpected operator: {}", operator)),
+            };
+            if level < minimum {
+                break;
+            }
+            self.position += 1;
+            let right = try!(self.expression(if right_associative { level } else { level + 1 }));
+            left = try!(apply(operator, left, right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Number, String> {
+        match self.next() {
+            Some(&Token::Number(number)) => Ok(number),
+            Some(&Token::Open) => {
+                let value = try!(self.expression(0));
+                match self.next() {
+                    Some(&Token::Close) => Ok(value),
+                    _ => Err("missing )".to_string()),
+                }
+            },
+            Some(&Token::Operator(operator)) if operator == "-" || operator == "+" || operator == "!" || operator == "~" => {
+                let value = try!(self.unary());
+                match operator {
+                    "-" => match value {
+                        Number::Integer(value) => overflow(value.checked_neg()).map(Number::Integer),
+                        Number::Float(value) => Ok(Number::Float(-value)),
+                    },
+                    "!" => Ok(boolean(!value.is_true())),
+                    "~" => value.integer(operator).map(|value| Number::Integer(!value)),
+                    _ => Ok(value),
+                }
+            },
+            Some(&Token::Operator(operator)) => Err(format!("unexpected operator: {}", operator)),
+            Some(&Token::Close) => Err("unexpected )".to_string()),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+/// Evaluates an arithmetic expression. Names in it are replaced by the numbers `lookup` returns
+/// for them.
+pub fn evaluate<F>(expression: &str, lookup: F) -> Result<Number, String>
+    where F: Fn(&str) -> Option<String>
+{
+    let tokens = try!(tokenize(expression, &lookup));
+    if tokens.is_empty() {
+        return Err("empty expression".to_string());
+    }
+    let mut parser = Parser { tokens: tokens, position: 0 };
+    let value = try!(parser.expression(0));
+    match parser.peek() {
+        None => Ok(value),
+        Some(&Token::Close) => Err("unexpected )".to_string()),
+        Some(_) => Err("expected an operator".to_string()),
+    }
+}
+
+/// Evaluates the arguments as one arithmetic expression and prints the result.
+pub fn math<I: IntoIterator>(args: I, variables: &Variables) -> i32
+    where I::Item: AsRef<str>
+{
+    let expression = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect::<Vec<String>>().join(" ");
+    match evaluate(&expression, |name| variables.get_var(name)) {
+        Ok(value) => {
+            println!("{}", value);
+            SUCCESS
+        },
+        Err(message) => {
+            error("math", message);
+            FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<String, String> {
+        evaluate(expression, |name| match name {
+            "x" => Some("4".to_string()),
+            "word" => Some("four".to_string()),
+            _ => None,
+        }).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn integer_operators_and_precedence() {
+        assert_eq!(Ok("7".to_string()), eval("1 + 2 * 3"));
+        assert_eq!(Ok("9".to_string()), eval("(1 + 2) * 3"));
+        assert_eq!(Ok("1".to_string()), eval("7 % 3"));
+        assert_eq!(Ok("3".to_string()), eval("7 / 2"));
+        assert_eq!(Ok("512".to_string()), eval("2 ** 3 ** 2"));
+        assert_eq!(Ok("-3".to_string()), eval("-(1 + 2)"));
+        assert_eq!(Ok("5".to_string()), eval("x + 1"));
+    }
+
+    #[test]
+    fn float_operators() {
+        assert_eq!(Ok("3.5".to_string()), eval("7 / 2.0"));
+        assert_eq!(Ok("0.5".to_string()), eval("2 ** -1"));
+        assert_eq!(Ok("0.002".to_string()), eval("2e-3"));
+        assert_eq!(Ok("1".to_string()), eval("1.5 > 1"));
+    }
+
+    #[test]
+    fn comparisons_and_bitwise_operators() {
+        assert_eq!(Ok("1".to_string()), eval("x == 4 && 2 <= 3"));
+        assert_eq!(Ok("0".to_string()), eval("!(x != 5)"));
+        assert_eq!(Ok("6".to_string()), eval("0x2 | 4"));
+        assert_eq!(Ok("16".to_string()), eval("1 << x"));
+        assert_eq!(Ok("-1".to_string()), eval("~0"));
+        assert_eq!(Ok("3".to_string()), eval("5 ^ 6"));
+    }
+
+    #[test]
+    fn errors_instead_of_zero() {
+        assert_eq!(Err("integer overflow".to_string()), eval("9223372036854775807 + 1"));
+        assert_eq!(Err("integer overflow".to_string()), eval("2 ** 64"));
+        assert_eq!(Err("number too large: 99999999999999999999".to_string()), eval("99999999999999999999"));
+        assert_eq!(Err("division by zero".to_string()), eval("1 / (x - 4)"));
+        assert_eq!(Err("invalid number: 12abc".to_string()), eval("12abc"));
+        assert_eq!(Err("word: not a number: four".to_string()), eval("word + 1"));
+        assert_eq!(Err("y: undefined variable".to_string()), eval("y + 1"));
+        assert_eq!(Err("operator & requires integer operands".to_string()), eval("1.5 & 1"));
+        assert_eq!(Err("missing )".to_string()), eval("(1 + 2"));
+        assert_eq!(Err("unexpected )".to_string()), eval("1 + 2)"));
+        assert_eq!(Err("unexpected end of expression".to_string()), eval("1 +"));
+        assert_eq!(Err("expected an operator".to_string()), eval("1 2"));
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(Ok(Number::Integer(-12)), Number::parse("-12"));
+        assert_eq!(Ok(Number::Integer(255)), Number::parse("0xff"));
+        assert_eq!(Ok(Number::Float(1.5)), Number::parse(" 1.5 "));
+        assert!(Number::parse("10abc").is_err());
+        assert!(Number::parse("inf").is_err());
+        assert!(Number::parse("").is_err());
+    }
+}
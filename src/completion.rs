@@ -0,0 +1,358 @@
+//! Candidates for completing the word under the cursor in the line editor
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use glob::Pattern;
+
+use super::variables::Variables;
+use super::diagnostics::error;
+use super::status::{SUCCESS, FAILURE};
+
+/// Words after which the next word is a command again, as in `if grep -q foo file`
+const COMMAND_PREFIXES: [&'static str; 6] = ["if", "else", "elif", "while", "not", "!"];
+
+/// The word that ends at the cursor.
+#[derive(Debug, PartialEq)]
+pub struct CurrentWord<'a> {
+    /// Byte index of the start of the word in the line
+    pub start: usize,
+    pub word: &'a str,
+    /// Whether the word is in the position of a command name
+    pub is_command: bool,
+    /// The name of the command the word is an argument of, if it is not a command itself
+    pub command: &'a str,
+    /// The text from the name of that command up to the word
+    pub command_line: &'a str,
+}
+
+/// Finds the word that ends at the end of `line`, which is the text before the cursor.
+pub fn current_word(line: &str) -> CurrentWord {
+    let mut start = 0;
+    let mut is_command = true;
+    let mut command_start = 0;
+    let mut quote: Option<char> = None;
+    for (index, character) in line.char_indices() {
+        if let Some(quote_character) = quote {
+            if character == quote_character {
+                quote = None;
+            }
+            continue;
+        }
+        match character {
+            '"' | '\'' => quote = Some(character),
+            '|' | ';' | '&' | '(' => {
+                start = index + 1;
+                is_command = true;
+                command_start = start;
+            },
+            '<' | '>' => {
+                start = index + 1;
+                is_command = false;
+            },
+            ' ' | '\t' => {
+                let word = &line[start..index];
+                if word.is_empty() {
+                    if is_command {
+                        command_start = index + 1;
+                    }
+                } else if is_command {
+                    if COMMAND_PREFIXES.contains(&word) {
+                        command_start = index + 1;
+                    } else {
+                        is_command = false;
+                    }
+                }
+                start = index + 1;
+            },
+            _ => (),
+        }
+    }
+    let command_line = if is_command { "" } else { line[command_start..start].trim() };
+    CurrentWord {
+        start: start,
+        word: &line[start..],
+        is_command: is_command,
+        command: command_line.split_whitespace().next().unwrap_or(""),
+        command_line: command_line,
+    }
+}
+
+/// Returns the names that start with the prefix, sorted and without duplicates.
+pub fn complete_names(prefix: &str, names: Vec<String>) -> Vec<String> {
+    let mut candidates: Vec<String> = names.into_iter().filter(|name| name.starts_with(prefix)).collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+/// If the word ends with a `$NAME`, returns the word completed with each variable name that
+/// starts with `NAME`.
+pub fn complete_variable(word: &str, names: Vec<String>) -> Option<Vec<String>> {
+    let dollar = match word.rfind('$') {
+        Some(dollar) => dollar,
+        None => return None,
+    };
+    let (before, prefix) = (&word[..dollar + 1], &word[dollar + 1..]);
+    if !Variables::is_valid_variable_name(prefix) {
+        return None;
+    }
+    Some(complete_names(prefix, names).into_iter().map(|name| before.to_string() + &name).collect())
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+/// The names of the executable files in the directories of `$PATH`.
+pub fn executables() -> Vec<String> {
+    let mut names = vec![];
+    if let Some(paths) = env::var_os("PATH") {
+        for directory in env::split_paths(&paths) {
+            if let Ok(entries) = fs::read_dir(directory) {
+                for entry in entries.filter_map(Result::ok) {
+                    if is_executable(&entry.path()) {
+                        names.push(entry.file_name().to_string_lossy().into_owned());
+                    }
+                }
+            }
+        }
+    }
+    names
+}
+
+/// Completes the path that was typed as `word`, and is `expanded` after tilde expansion.
+/// Directories get a trailing `/`. With `executables_only`, only directories and executable
+/// files are candidates.
+pub fn complete_path(word: &str, expanded: &str, executables_only: bool) -> Vec<String> {
+    // A lone `~user` style word only needs its slash
+    if !word.contains('/') && word != expanded {
+        return if Path::new(expanded).is_dir() { vec![word.to_string() + "/"] } else { vec![] };
+    }
+    let (typed_directory, directory, prefix) = match (word.rfind('/'), expanded.rfind('/')) {
+        (Some(typed), Some(slash)) => (&word[..typed + 1], &expanded[..slash + 1], &expanded[slash + 1..]),
+        _ => ("", "./", expanded),
+    };
+    let mut candidates = vec![];
+    if let Ok(entries) = fs::read_dir(directory) {
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Hidden files are only listed when asked for
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                continue;
+            }
+            let path = entry.path();
+            if path.is_dir() {
+                candidates.push(format!("{}{}/", typed_directory, name));
+            } else if !executables_only || is_executable(&path) {
+                candidates.push(format!("{}{}", typed_directory, name));
+            }
+        }
+    }
+    candidates.sort();
+    candidates
+}
+
+/// Completions registered for a command with the `complete` builtin.
+#[derive(Clone, Default)]
+pub struct CompletionSpec {
+    /// A fixed list of candidates
+    pub words: Vec<String>,
+    /// A function whose output supplies candidates
+    pub function: Option<String>,
+    /// A glob that the names of the files offered as candidates must match
+    pub glob: Option<String>,
+}
+
+impl CompletionSpec {
+    /// Whether a path candidate is allowed by the glob. Directories always are, so that paths
+    /// can still be completed through them.
+    pub fn allows_path(&self, candidate: &str) -> bool {
+        match self.glob {
+            Some(ref glob) => {
+                candidate.ends_with('/') ||
+                Pattern::new(glob).map(|pattern| pattern.matches(candidate_name(candidate))).unwrap_or(false)
+            },
+            None => false,
+        }
+    }
+
+    fn print(&self, command: &str) {
+        let mut line = "complete".to_string();
+        if !self.words.is_empty() {
+            line.push_str(&format!(" -w \"{}\"", self.words.join(" ")));
+        }
+        if let Some(ref function) = self.function {
+            line.push_str(&format!(" -f {}", function));
+        }
+        if let Some(ref glob) = self.glob {
+            line.push_str(&format!(" -g \"{}\"", glob));
+        }
+        println!("{} {}", line, command);
+    }
+}
+
+/// The name of the file a path candidate refers to, keeping the trailing `/` of a directory.
+pub fn candidate_name(candidate: &str) -> &str {
+    match candidate.trim_right_matches('/').rfind('/') {
+        Some(slash) => &candidate[slash + 1..],
+        None => candidate,
+    }
+}
+
+/// The completion specs of every command that has some.
+pub struct Completions {
+    specs: BTreeMap<String, CompletionSpec>,
+}
+
+impl Default for Completions {
+    fn default() -> Completions {
+        Completions { specs: BTreeMap::new() }
+    }
+}
+
+impl Completions {
+    pub fn get(&self, command: &str) -> Option<&CompletionSpec> {
+        self.specs.get(command)
+    }
+
+    /// Registers, removes or prints the completion specs of commands.
+    pub fn complete<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let args: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+        let mut spec = CompletionSpec::default();
+        let mut has_spec = false;
+        let mut remove = false;
+        let mut commands = vec![];
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-r" => remove = true,
+                "-w" | "-f" | "-g" => {
+                    let value = match iter.next() {
+                        Some(value) => value.clone(),
+                        None => {
+                            error("complete", format!("option {} requires an argument", arg));
+                            return FAILURE;
+                        }
+                    };
+                    match arg.as_str() {
+                        "-w" => spec.words.extend(value.split_whitespace().map(|word| word.to_string())),
+                        "-f" => spec.function = Some(value),
+                        _ => {
+                            if let Err(err) = Pattern::new(&value) {
+                                error("complete", format!("invalid glob {}: {}", value, err.msg));
+                                return FAILURE;
+                            }
+                            spec.glob = Some(value);
+                        },
+                    }
+                    has_spec = true;
+                },
+                _ => commands.push(arg.clone()),
+            }
+        }
+
+        if remove {
+            for command in &commands {
+                if self.specs.remove(command).is_none() {
+                    error("complete", format!("no completion specification for {}", command));
+                    return FAILURE;
+                }
+            }
+        } else if has_spec {
+            if commands.is_empty() {
+                error("complete", "no command given");
+                return FAILURE;
+            }
+            for command in commands {
+                self.specs.insert(command, spec.clone());
+            }
+        } else if commands.is_empty() {
+            for (command, spec) in &self.specs {
+                spec.print(command);
+            }
+        } else {
+            for command in &commands {
+                match self.specs.get(command) {
+                    Some(spec) => spec.print(command),
+                    None => {
+                        error("complete", format!("no completion specification for {}", command));
+                        return FAILURE;
+                    }
+                }
+            }
+        }
+        SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word<'a>(start: usize, word: &'a str, command_line: &'a str) -> CurrentWord<'a> {
+        CurrentWord {
+            start: start,
+            word: word,
+            is_command: command_line.is_empty(),
+            command: command_line.split_whitespace().next().unwrap_or(""),
+            command_line: command_line,
+        }
+    }
+
+    #[test]
+    fn command_and_argument_words() {
+        assert_eq!(word(0, "ec", ""), current_word("ec"));
+        assert_eq!(word(5, "sr", "echo"), current_word("echo sr"));
+        assert_eq!(word(11, "gr", ""), current_word("cat file | gr"));
+        assert_eq!(word(7, "gr", ""), current_word("if not gr"));
+        assert_eq!(word(11, "", "echo \"a |\""), current_word("echo \"a |\" "));
+        assert_eq!(word(6, "lo", "echo >"), current_word("echo >lo"));
+    }
+
+    #[test]
+    fn words_of_the_current_command() {
+        let current = current_word("cat file | if git commit --am");
+        assert_eq!("git", current.command);
+        assert_eq!("git commit", current.command_line);
+        assert_eq!("--am", current.word);
+    }
+
+    #[test]
+    fn complete_variable_names() {
+        let names = vec!["HOME".to_string(), "HISTORY_FILE".to_string(), "PATH".to_string()];
+        assert_eq!(Some(vec!["$HISTORY_FILE".to_string(), "$HOME".to_string()]),
+                   complete_variable("$H", names.clone()));
+        assert_eq!(Some(vec!["dir/$PATH".to_string()]), complete_variable("dir/$PA", names.clone()));
+        assert_eq!(None, complete_variable("src/ma", names));
+    }
+
+    #[test]
+    fn complete_paths() {
+        assert_eq!(vec!["src/main.rs".to_string()], complete_path("src/mai", "src/mai", false));
+        assert_eq!(vec!["src/".to_string()], complete_path("sr", "sr", false));
+        assert!(complete_path("src/", "src/", true).is_empty());
+    }
+
+    #[test]
+    fn register_and_remove_specs() {
+        let mut completions = Completions::default();
+        assert_eq!(SUCCESS, completions.complete(vec!["complete", "-w", "build test", "-g", "*.toml", "cargo"]));
+        {
+            let spec = completions.get("cargo").unwrap();
+            assert_eq!(vec!["build".to_string(), "test".to_string()], spec.words);
+            assert!(spec.allows_path("src/"));
+            assert!(spec.allows_path("dir/Cargo.toml"));
+            assert!(!spec.allows_path("src/main.rs"));
+        }
+        assert_eq!(FAILURE, completions.complete(vec!["complete", "-f"]));
+        assert_eq!(SUCCESS, completions.complete(vec!["complete", "-r", "cargo"]));
+        assert!(completions.get("cargo").is_none());
+        assert_eq!(FAILURE, completions.complete(vec!["complete", "-r", "cargo"]));
+    }
+}
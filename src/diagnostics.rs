@@ -0,0 +1,14 @@
+//! Error messages of the shell and its builtins
+//!
+//! Errors are written to stderr rather than stdout, so that they are seen even when the output
+//! of a command is piped, redirected or captured by a command substitution.
+
+use std::fmt::Display;
+use std::io::{self, Write};
+
+/// Reports an error as `ion: <context>: <message>`, where the context is the builtin, option or
+/// file the error is about.
+pub fn error<M: Display>(context: &str, message: M) {
+    let stderr = io::stderr();
+    let _ = writeln!(stderr.lock(), "ion: {}: {}", context, message);
+}
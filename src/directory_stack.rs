@@ -2,6 +2,7 @@ use std::collections::VecDeque;
 use std::env::{set_current_dir, current_dir, home_dir};
 use std::path::PathBuf;
 use variables::Variables;
+use super::diagnostics::error;
 use super::status::{SUCCESS, FAILURE};
 
 pub struct DirectoryStack {
@@ -36,11 +37,11 @@ impl DirectoryStack {
     {
         if let Some(dir) = self.get_previous_dir() {
             if let Err(err) = set_current_dir(dir) {
-                println!("{}: Failed to switch to directory {}", err, dir.display());
+                error("popd", format!("{}: {}", dir.display(), err));
                 return FAILURE;
             }
         } else {
-            println!("Directory stack is empty");
+            error("popd", "directory stack is empty");
             return FAILURE;
         }
         self.dirs.pop_front();
@@ -52,11 +53,11 @@ impl DirectoryStack {
         where I::Item: AsRef<str>
     {
         if let Some(dir) = args.into_iter().nth(1) {
-            let result = self.change_and_push_dir(dir.as_ref(), variables);
+            let result = self.change_and_push_dir("pushd", dir.as_ref(), variables);
             self.print_dirs();
             result
         } else {
-            println!("No directory provided");
+            error("pushd", "no directory given");
             FAILURE
         }
     }
@@ -69,7 +70,7 @@ impl DirectoryStack {
             if dir == "-" {
                 self.switch_to_previous_directory(variables)
             } else {
-                self.change_and_push_dir(dir, variables)
+                self.change_and_push_dir("cd", dir, variables)
             }
         } else {
             self.switch_to_home_directory(variables)
@@ -79,13 +80,13 @@ impl DirectoryStack {
     fn switch_to_home_directory(&mut self, variables: &Variables) -> i32 {
         if let Some(home) = home_dir() {
             if let Some(home) = home.to_str() {
-                self.change_and_push_dir(home, variables)
+                self.change_and_push_dir("cd", home, variables)
             } else {
-                println!("Failed to convert home directory to str");
+                error("cd", "home directory is not valid unicode");
                 FAILURE
             }
         } else {
-            println!("Failed to get home directory");
+            error("cd", "could not get home directory");
             FAILURE
         }
     }
@@ -95,9 +96,9 @@ impl DirectoryStack {
                                 .map(|path| path.to_string_lossy().to_string()) {
             self.dirs.remove(1);
             println!("{}", prev);
-            self.change_and_push_dir(&prev, variables)
+            self.change_and_push_dir("cd", &prev, variables)
         } else {
-            println!("No previous directory to switch to");
+            error("cd", "no previous directory");
             FAILURE
         }
     }
@@ -110,14 +111,16 @@ impl DirectoryStack {
         }
     }
 
-    pub fn change_and_push_dir(&mut self, dir: &str, variables: &Variables) -> i32 {
+    /// Changes to the directory and pushes it on the stack. Errors are reported as coming from the
+    /// builtin `command`.
+    pub fn change_and_push_dir(&mut self, command: &str, dir: &str, variables: &Variables) -> i32 {
         match (set_current_dir(dir), current_dir()) {
             (Ok(()), Ok(cur_dir)) => {
                 self.push_dir(cur_dir, variables);
                 SUCCESS
             }
             (Err(err), _) => {
-                println!("Failed to set current dir to {}: {}", dir, err);
+                error(command, format!("{}: {}", dir, err));
                 FAILURE
             }
             (_, _) => FAILURE, // This should not happen
@@ -1,25 +1,120 @@
-use super::to_num::ToNum;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use super::arithmetic::Number;
 use super::peg::Pipeline;
+use super::diagnostics::error;
 use super::status::{SUCCESS, FAILURE};
 
-pub fn is_flow_control_command(command: &str) -> bool {
-    command == "end" || command == "if" || command == "else"
+/// Whether the command opens a block that is closed by `end`.
+pub fn is_block_command(command: &str) -> bool {
+    command == "if" || command == "for" || command == "while" || command == "fn"
+}
+
+/// Whether the operator is one of the comparisons understood by `test`.
+pub fn is_comparison(operator: &str) -> bool {
+    match operator {
+        "==" | "=" | "!=" | ">" | ">=" | "<" | "<=" | "-eq" | "-ne" | "-gt" | "-ge" | "-lt" | "-le" => true,
+        _ => false,
+    }
+}
+
+fn compare(left: &str, operator: &str, right: &str) -> Option<bool> {
+    match operator {
+        "==" | "=" => return Some(left == right),
+        "!=" => return Some(left != right),
+        ">" | "-gt" | ">=" | "-ge" | "<" | "-lt" | "<=" | "-le" | "-eq" | "-ne" => (),
+        _ => {
+            error("test", format!("unknown comparison: {}", operator));
+            return None;
+        }
+    }
+    let (left, right) = match (Number::parse(left), Number::parse(right)) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(message), _) | (_, Err(message)) => {
+            error("test", message);
+            return None;
+        }
+    };
+    Some(match operator {
+        ">" | "-gt" => left > right,
+        ">=" | "-ge" => left >= right,
+        "<" | "-lt" => left < right,
+        "<=" | "-le" => left <= right,
+        "-eq" => left == right,
+        _ => left != right,
+    })
+}
+
+fn check(operator: &str, operand: &str) -> Option<bool> {
+    let metadata = fs::metadata(operand).ok();
+    match operator {
+        "-z" => Some(operand.is_empty()),
+        "-n" => Some(!operand.is_empty()),
+        "-e" => Some(metadata.is_some()),
+        "-f" => Some(metadata.map_or(false, |metadata| metadata.is_file())),
+        "-d" => Some(metadata.map_or(false, |metadata| metadata.is_dir())),
+        "-s" => Some(metadata.map_or(false, |metadata| metadata.len() > 0)),
+        "-x" => Some(metadata.map_or(false, |metadata| metadata.permissions().mode() & 0o111 != 0)),
+        _ => {
+            error("test", format!("unknown test: {}", operator));
+            None
+        }
+    }
+}
+
+/// Evaluates the arguments of the `test` builtin, which is either a comparison like
+/// `test $a == b` or `test $count -lt 10`, or a check like `test -f file` or `test -z $var`.
+/// A leading `!` negates the result.
+pub fn test<I: IntoIterator>(args: I) -> i32
+    where I::Item: AsRef<str>
+{
+    let args: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+    let (negate, args) = match args.first() {
+        Some(first) if first == "!" => (true, &args[1..]),
+        _ => (false, &args[..]),
+    };
+    let result = match args.len() {
+        0 => Some(false),
+        1 => Some(!args[0].is_empty()),
+        2 => check(&args[0], &args[1]),
+        3 => compare(&args[0], &args[1], &args[2]),
+        _ => {
+            error("test", "too many arguments");
+            None
+        }
+    };
+    match result {
+        Some(value) if value != negate => SUCCESS,
+        _ => FAILURE,
+    }
 }
 
 #[derive(Clone)]
 pub enum Statement {
     For(String, Vec<String>),
+    While(Pipeline),
     Function(String, Vec<String>),
     If,
     Default
 }
 
+/// Set by `break` and `continue` until the innermost loop acts on it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoopControl {
+    Break,
+    Continue,
+}
+
 pub struct CodeBlock {
     pub pipelines: Vec<Pipeline>,
 }
 
 pub struct Mode {
+    /// Whether the current branch of the if chain runs
     pub value: bool,
+    /// Whether any branch of the if chain has run so far
+    pub taken: bool,
 }
 
 pub struct FlowControl {
@@ -27,6 +122,12 @@ pub struct FlowControl {
     pub collecting_block: bool,
     pub current_block: CodeBlock,
     pub current_statement: Statement, /* pub prompt: &'static str,  // Custom prompt while collecting code block */
+    /// Keywords of the blocks opened inside the block being collected, innermost last. They are
+    /// collected along with their `end` and only interpreted when the outer block is executed.
+    pub nested_blocks: Vec<String>,
+    /// Number of loops currently being executed
+    pub loop_depth: usize,
+    pub loop_control: Option<LoopControl>,
 }
 
 impl Default for FlowControl {
@@ -36,6 +137,9 @@ impl Default for FlowControl {
             collecting_block: false,
             current_block: CodeBlock { pipelines: vec![] },
             current_statement: Statement::Default,
+            nested_blocks: vec![],
+            loop_depth: 0,
+            loop_control: None,
         }
     }
 }
@@ -45,46 +149,14 @@ impl FlowControl {
         self.modes.iter().any(|mode| !mode.value)
     }
 
-    pub fn if_<I: IntoIterator>(&mut self, args: I) -> i32
-        where I::Item: AsRef<str>
-    {
-        let mut args = args.into_iter(); // TODO why does the compiler want this to be mutable?
-        let value;
-        if let Some(left) = args.nth(1) {
-            let left = left.as_ref();
-            if let Some(cmp) = args.nth(0) {
-                let cmp = cmp.as_ref();
-                if let Some(right) = args.nth(0) {
-                    let right = right.as_ref();
-                    if cmp == "==" {
-                        value = left == right;
-                    } else if cmp == "!=" {
-                        value = left != right;
-                    } else if cmp == ">" {
-                        value = left.to_num_signed() > right.to_num_signed();
-                    } else if cmp == ">=" {
-                        value = left.to_num_signed() >= right.to_num_signed();
-                    } else if cmp == "<" {
-                        value = left.to_num_signed() < right.to_num_signed();
-                    } else if cmp == "<=" {
-                        value = left.to_num_signed() <= right.to_num_signed();
-                    } else {
-                        println!("Unknown comparison: {}", cmp);
-                        return FAILURE;
-                    }
-                } else {
-                    println!("No right hand side");
-                    return FAILURE;
-                }
-            } else {
-                println!("No comparison operator");
-                return FAILURE;
-            }
-        } else {
-            println!("No left hand side");
-            return FAILURE;
-        }
-        self.modes.insert(0, Mode { value: value });
+    /// Opens a block inside a branch that is not taken, so that its `end` does not close the
+    /// branch's `if`.
+    pub fn skip_block(&mut self) {
+        self.modes.insert(0, Mode { value: false, taken: true });
+    }
+
+    pub fn if_(&mut self, value: bool) -> i32 {
+        self.modes.insert(0, Mode { value: value, taken: value });
         self.current_statement = Statement::If;
         SUCCESS
     }
@@ -92,11 +164,27 @@ impl FlowControl {
     pub fn else_<I: IntoIterator>(&mut self, _: I) -> i32
         where I::Item: AsRef<str>
     {
+        self.else_if(true)
+    }
+
+    /// Whether the condition of an `else if` has to be evaluated, which is only the case when no
+    /// earlier branch of its chain was taken and the chain itself is not being skipped.
+    pub fn else_if_pending(&self) -> bool {
+        match self.modes.split_first() {
+            Some((mode, enclosing)) => !mode.taken && enclosing.iter().all(|mode| mode.value),
+            None => false,
+        }
+    }
+
+    /// Switches the innermost if chain to its next branch, which runs if its condition `value`
+    /// holds and no earlier branch was taken.
+    pub fn else_if(&mut self, value: bool) -> i32 {
         if let Some(mode) = self.modes.get_mut(0) {
-            mode.value = !mode.value;
+            mode.value = value && !mode.taken;
+            mode.taken = mode.taken || mode.value;
             SUCCESS
         } else {
-            println!("Syntax error: else found with no previous if");
+            error("else", "found with no previous if");
             FAILURE
         }
     }
@@ -105,7 +193,7 @@ impl FlowControl {
         where I::Item: AsRef<str>
     {
         if self.modes.is_empty() {
-            println!("Syntax error: end found outside of a block");
+            error("end", "found outside of a block");
             FAILURE
         } else {
             self.modes.remove(0);
@@ -120,23 +208,53 @@ impl FlowControl {
         if let Some(variable) = args.nth(1).map(|var| var.as_ref().to_string()) {
             if let Some(in_) = args.nth(0) {
                 if in_.as_ref() != "in" {
-                    println!("For loops must have 'in' as the second argument");
+                    error("for", "the second argument must be 'in'");
                     return FAILURE;
                 }
             } else {
-                println!("For loops must have 'in' as the second argument");
+                error("for", "the second argument must be 'in'");
                 return FAILURE;
             }
             let values: Vec<String> = args.map(|value| value.as_ref().to_string()).collect();
             self.current_statement = Statement::For(variable, values);
             self.collecting_block = true;
         } else {
-            println!("For loops must have a variable name as the first argument");
+            error("for", "missing variable name");
             return FAILURE;
         }
         SUCCESS
     }
 
+    /// Starts collecting the body of a while loop. The condition is the unexpanded `while`
+    /// pipeline, which is evaluated again before each iteration.
+    pub fn while_(&mut self, condition: Pipeline) -> i32 {
+        self.current_statement = Statement::While(condition);
+        self.collecting_block = true;
+        SUCCESS
+    }
+
+    pub fn break_<I: IntoIterator>(&mut self, _: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        self.loop_control_(LoopControl::Break, "break")
+    }
+
+    pub fn continue_<I: IntoIterator>(&mut self, _: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        self.loop_control_(LoopControl::Continue, "continue")
+    }
+
+    fn loop_control_(&mut self, control: LoopControl, name: &str) -> i32 {
+        if self.loop_depth == 0 {
+            error(name, "found outside of a loop");
+            FAILURE
+        } else {
+            self.loop_control = Some(control);
+            SUCCESS
+        }
+    }
+
     pub fn fn_<I: IntoIterator>(&mut self, args: I) -> i32
         where I::Item: AsRef<str>
     {
@@ -146,9 +264,43 @@ impl FlowControl {
             let values: Vec<String> = args.map(|value| value.as_ref().to_string()).collect();
             self.current_statement = Statement::Function(name.as_ref().to_string(), values);
         } else {
-            println!("Functions must have the function name as the first argument");
+            error("fn", "missing function name");
             return FAILURE;
         }
         SUCCESS
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status::{FAILURE, SUCCESS};
+
+    #[test]
+    fn test_comparisons() {
+        assert_eq!(SUCCESS, test(vec!["test", "a", "==", "a"]));
+        assert_eq!(FAILURE, test(vec!["test", "a", "!=", "a"]));
+        assert_eq!(SUCCESS, test(vec!["test", "10", "-gt", "9"]));
+        assert_eq!(SUCCESS, test(vec!["test", "-3", "<=", "2"]));
+        assert_eq!(SUCCESS, test(vec!["test", "2.5", ">", "2"]));
+        assert_eq!(FAILURE, test(vec!["test", "10abc", "-gt", "9"]));
+        assert_eq!(FAILURE, test(vec!["test", "a", "~", "b"]));
+    }
+
+    #[test]
+    fn test_checks() {
+        assert_eq!(SUCCESS, test(vec!["test", "-z", ""]));
+        assert_eq!(SUCCESS, test(vec!["test", "-n", "value"]));
+        assert_eq!(SUCCESS, test(vec!["test", "-d", "src"]));
+        assert_eq!(FAILURE, test(vec!["test", "-f", "src"]));
+        assert_eq!(SUCCESS, test(vec!["test", "-e", "Cargo.toml"]));
+    }
+
+    #[test]
+    fn test_negation_and_arity() {
+        assert_eq!(FAILURE, test(vec!["test", "!", "-e", "Cargo.toml"]));
+        assert_eq!(FAILURE, test(vec!["test"]));
+        assert_eq!(SUCCESS, test(vec!["test", "word"]));
+        assert_eq!(FAILURE, test(vec!["test", "a", "b", "c", "d"]));
+    }
+}
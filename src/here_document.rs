@@ -0,0 +1,162 @@
+//! Here-documents, whose bodies are the lines following the command they are given to
+
+use super::quoting::split_quotes;
+use super::substitution::find_closing_paren;
+use super::variables::Variables;
+
+/// Returns the length of the word at the start of the text, which ends at whitespace or at a
+/// character that separates commands, unless it is quoted or escaped.
+fn word_length(text: &str) -> usize {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (index, character) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if let Some(quote_character) = quote {
+            if character == quote_character {
+                quote = None;
+            }
+        } else {
+            match character {
+                '\\' => escaped = true,
+                '"' | '\'' => quote = Some(character),
+                ' ' | '\t' | '\r' | ';' | '&' | '|' | '<' | '>' => return index,
+                _ => (),
+            }
+        }
+    }
+    text.len()
+}
+
+/// The delimiter of a here-document without its quotes.
+pub fn unquote(word: &str) -> String {
+    split_quotes(word).into_iter().map(|part| part.text).collect()
+}
+
+/// The here-documents started on a line, in order: their delimiters, without quotes, and whether
+/// leading tabs are stripped from their lines, as with `<<-`.
+fn delimiters(line: &str) -> Vec<(String, bool)> {
+    let mut delimiters = vec![];
+    let mut quote: Option<char> = None;
+    let mut index = 0;
+    while index < line.len() {
+        let rest = &line[index..];
+        let character = rest.chars().next().unwrap();
+        index += character.len_utf8();
+        if let Some(quote_character) = quote {
+            if character == quote_character {
+                quote = None;
+            } else if character == '\\' && quote_character == '"' {
+                index += rest[1..].chars().next().map_or(0, |escaped| escaped.len_utf8());
+            }
+            continue;
+        }
+        match character {
+            '\\' => index += rest[1..].chars().next().map_or(0, |escaped| escaped.len_utf8()),
+            '"' | '\'' => quote = Some(character),
+            '#' => break,
+            '$' if rest[1..].starts_with('#') => index += 1,
+            '$' if rest[1..].starts_with('(') || rest[1..].starts_with('{') => {
+                let start = index + 1;
+                let end = if rest[1..].starts_with('(') {
+                    find_closing_paren(line, start)
+                } else {
+                    Variables::find_closing_brace(line, start)
+                };
+                index = end.map_or(line.len(), |end| end + 1);
+            },
+            // A here-string
+            '<' if rest.starts_with("<<<") => index += 2,
+            '<' if rest.starts_with("<<") => {
+                let mut after = &rest[2..];
+                let strip_tabs = after.starts_with('-');
+                if strip_tabs {
+                    after = &after[1..];
+                }
+                let word = after.trim_left_matches(|character| character == ' ' || character == '\t');
+                let length = word_length(word);
+                if length > 0 {
+                    delimiters.push((unquote(&word[..length]), strip_tabs));
+                }
+                index += rest.len() - word.len() + length - 1;
+            },
+            _ => (),
+        }
+    }
+    delimiters
+}
+
+/// Takes the bodies of the here-documents out of the code, leaving their lines empty so that the
+/// lines after them keep their numbers. Returns the code, the bodies in the order their `<<`
+/// appear, and the delimiter of the last body if the code ends before it.
+pub fn extract(code: &str) -> (String, Vec<String>, Option<String>) {
+    let mut lines: Vec<&str> = vec![];
+    let mut bodies = vec![];
+    let mut pending: Vec<(String, bool)> = vec![];
+    let mut body = String::new();
+    let last = code.split('\n').count() - 1;
+    for (number, line) in code.split('\n').enumerate() {
+        if pending.is_empty() || (number == last && line.is_empty()) {
+            pending.extend(delimiters(line));
+            lines.push(line);
+            continue;
+        }
+        let text = if pending[0].1 { line.trim_left_matches('\t') } else { line };
+        if text.trim_right_matches('\r') == pending[0].0 {
+            bodies.push(body.clone());
+            body.clear();
+            pending.remove(0);
+        } else {
+            body.push_str(text);
+            body.push('\n');
+        }
+        lines.push("");
+    }
+    let missing = pending.first().map(|&(ref delimiter, _)| delimiter.clone());
+    if !pending.is_empty() {
+        // Unterminated bodies end with the code
+        bodies.push(body);
+        for _ in 1..pending.len() {
+            bodies.push(String::new());
+        }
+    }
+    (lines.join("\n"), bodies, missing)
+}
+
+/// Whether the code has a here-document whose delimiter was not reached yet, in which case the
+/// prompt keeps reading lines.
+pub fn is_unterminated(code: &str) -> bool {
+    extract(code).2.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_are_taken_out() {
+        let (code, bodies, missing) = extract("cat <<EOF\n$a\n  b\nEOF\necho done");
+        assert_eq!("cat <<EOF\n\n\n\necho done", code);
+        assert_eq!(vec!["$a\n  b\n".to_string()], bodies);
+        assert_eq!(None, missing);
+    }
+
+    #[test]
+    fn several_quoted_and_tab_stripped_delimiters() {
+        let (_, bodies, _) = extract("cat <<'A' <<-\"B\" <<<here # <<C\na\nA\n\t\tb\n\tB");
+        assert_eq!(vec!["a\n".to_string(), "b\n".to_string()], bodies);
+    }
+
+    #[test]
+    fn shift_and_quotes_are_not_here_documents() {
+        assert_eq!(0, extract("echo '<<A' \"<<B\" \\<<C $((1 << 2))").1.len());
+    }
+
+    #[test]
+    fn unterminated_body() {
+        assert!(is_unterminated("cat <<EOF\nline"));
+        assert!(!is_unterminated("cat <<EOF\nline\nEOF"));
+        assert_eq!((String::from("cat <<EOF\n\n"), vec!["line\n".to_string()], Some("EOF".to_string())),
+                   extract("cat <<EOF\nline\n"));
+    }
+}
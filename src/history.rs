@@ -2,6 +2,7 @@ use std::collections::VecDeque;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use variables::Variables;
+use super::diagnostics::error;
 use super::status::SUCCESS;
 
 pub struct History {
@@ -70,40 +71,51 @@ impl History {
                     };
 
                     if let Err(message) = file.seek(SeekFrom::Start(seek_point)) {
-                        println!("ion: unable to seek in history file: {}", message);
+                        error("history", format!("unable to seek in history file: {}", message));
                     }
 
                     let mut buffer: Vec<u8> = Vec::with_capacity(file_length - seek_point as usize);
                     if let Err(message) = file.read_to_end(&mut buffer) {
-                        println!("ion: unable to buffer history file: {}", message);
+                        error("history", format!("unable to buffer history file: {}", message));
                     }
 
                     if let Err(message) = file.set_len(0) {
-                        println!("ion: unable to truncate history file: {}", message);
+                        error("history", format!("unable to truncate history file: {}", message));
                     }
 
                     if let Err(message) = io::copy(&mut buffer.as_slice(), &mut file) {
-                        println!("ion: unable to write to history file: {}", message);
+                        error("history", format!("unable to write to history file: {}", message));
                     }
                 }
 
                 // Seek to end for appending
                 if let Err(message) = file.seek(SeekFrom::End(0)) {
-                    println!("ion: unable to seek in history file: {}", message);
+                    error("history", format!("unable to seek in history file: {}", message));
                 }
 
                 // Write the command to the history file.
                 if let Err(message) = file.write_all(command.as_bytes()) {
-                    println!("ion: unable to write to history file: {}", message);
+                    error("history", format!("unable to write to history file: {}", message));
                 }
                 if let Err(message) = file.write(b"\n") {
-                    println!("ion: unable to write to history file: {}", message);
+                    error("history", format!("unable to write to history file: {}", message));
                 }
             },
-            Err(message) => println!("ion: error opening file: {}", message)
+            Err(message) => error("history", format!("unable to open history file: {}", message))
         }
     }
 
+    /// The commands of this session, the most recent first.
+    pub fn commands(&self) -> &VecDeque<String> {
+        &self.history
+    }
+
+    /// The path of the history file, if one is set.
+    pub fn file_path(variables: &Variables) -> Option<String> {
+        let path = variables.expand_string("$HISTORY_FILE");
+        if path.is_empty() { None } else { Some(path) }
+    }
+
     /// Print the entire history list currently buffered to stdout directly.
     pub fn history<I: IntoIterator>(&self, _: I) -> i32
         where I::Item: AsRef<str>
@@ -1,9 +1,743 @@
-use std::io::stdin;
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, stdout, Read, Write};
+use std::mem;
+use libc;
 
+use super::completion::candidate_name;
+
+/// The shell's stdin, read without a buffer so that no more than what is asked for is taken
+/// from it. A builtin like `read` may be reading a file redirected to stdin, whose remaining lines
+/// must not be taken for the input of the shell once the redirection is undone.
+struct UnbufferedStdin;
+
+impl Read for UnbufferedStdin {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let count = unsafe { libc::read(0, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if count < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(count as usize)
+        }
+    }
+}
+
+/// Reads a line from stdin without any editing, one byte at a time so that nothing after the
+/// line is consumed. Returns `None` at the end of the input.
 pub fn readln() -> Option<String> {
-    let mut buffer = String::new();
-    match stdin().read_line(&mut buffer) {
-        Ok(_) => Some(buffer),
-        Err(_) => None,
+    let mut bytes = vec![];
+    while let Some(byte) = read_byte(&mut UnbufferedStdin) {
+        bytes.push(byte);
+        if byte == b'\n' {
+            break;
+        }
+    }
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Key {
+    Char(char),
+    Ctrl(char),
+    Alt(char),
+    Backspace,
+    Delete,
+    Enter,
+    Tab,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    WordLeft,
+    WordRight,
+    Unknown,
+}
+
+fn read_byte<R: Read>(input: &mut R) -> Option<u8> {
+    let mut byte = [0; 1];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return None,
+            Ok(_) => return Some(byte[0]),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Decodes the next key press from the terminal, which may span several bytes for escape
+/// sequences and UTF-8 characters. Returns `None` at the end of the input.
+pub fn read_key<R: Read>(input: &mut R) -> Option<Key> {
+    let byte = match read_byte(input) {
+        Some(byte) => byte,
+        None => return None,
+    };
+    Some(match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        127 | 8 => Key::Backspace,
+        27 => read_escape(input),
+        1...26 => Key::Ctrl((b'a' + byte - 1) as char),
+        0...31 => Key::Unknown,
+        _ => read_utf8(byte, input),
+    })
+}
+
+fn read_escape<R: Read>(input: &mut R) -> Key {
+    match read_byte(input) {
+        Some(b'[') => read_control_sequence(input),
+        Some(b'O') => {
+            match read_byte(input) {
+                Some(b'H') => Key::Home,
+                Some(b'F') => Key::End,
+                _ => Key::Unknown,
+            }
+        },
+        Some(b'b') => Key::WordLeft,
+        Some(b'f') => Key::WordRight,
+        Some(127) => Key::Alt('\x7f'),
+        Some(byte) if byte >= 32 && byte < 127 => Key::Alt(byte as char),
+        _ => Key::Unknown,
+    }
+}
+
+/// Decodes the rest of a `ESC [ parameters final` sequence, as sent by the arrow and editing keys.
+fn read_control_sequence<R: Read>(input: &mut R) -> Key {
+    let mut parameters = String::new();
+    loop {
+        match read_byte(input) {
+            Some(byte) if byte >= 0x30 && byte <= 0x3f => parameters.push(byte as char),
+            Some(byte) => {
+                // Ctrl and Alt make the arrows jump over words, e.g. `ESC [ 1 ; 5 D`
+                let modified = parameters.ends_with(";5") || parameters.ends_with(";3");
+                return match (byte, parameters.as_str()) {
+                    (b'A', _) => Key::Up,
+                    (b'B', _) => Key::Down,
+                    (b'C', _) if modified => Key::WordRight,
+                    (b'D', _) if modified => Key::WordLeft,
+                    (b'C', _) => Key::Right,
+                    (b'D', _) => Key::Left,
+                    (b'H', _) | (b'~', "1") | (b'~', "7") => Key::Home,
+                    (b'F', _) | (b'~', "4") | (b'~', "8") => Key::End,
+                    (b'~', "3") => Key::Delete,
+                    _ => Key::Unknown,
+                };
+            },
+            None => return Key::Unknown,
+        }
+    }
+}
+
+fn read_utf8<R: Read>(first: u8, input: &mut R) -> Key {
+    let length = if first >= 0xf0 {
+        4
+    } else if first >= 0xe0 {
+        3
+    } else if first >= 0xc0 {
+        2
+    } else {
+        1
+    };
+    let mut bytes = vec![first];
+    for _ in 1..length {
+        match read_byte(input) {
+            Some(byte) => bytes.push(byte),
+            None => return Key::Unknown,
+        }
+    }
+    match String::from_utf8(bytes).ok().and_then(|string| string.chars().next()) {
+        Some(character) => Key::Char(character),
+        None => Key::Unknown,
+    }
+}
+
+fn is_word_character(character: char) -> bool {
+    character.is_alphanumeric() || character == '_'
+}
+
+fn is_not_whitespace(character: char) -> bool {
+    !character.is_whitespace()
+}
+
+/// The text of the line being edited and the position of the cursor within it.
+#[derive(Default)]
+pub struct LineBuffer {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        for character in text.chars() {
+            self.chars.insert(self.cursor, character);
+            self.cursor += 1;
+        }
+    }
+
+    pub fn delete_before(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    pub fn delete_at(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.cursor += 1;
+        }
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.chars.len();
+    }
+
+    /// Position of the start of the word before the cursor
+    fn word_start(&self, is_word: fn(char) -> bool) -> usize {
+        let mut position = self.cursor;
+        while position > 0 && !is_word(self.chars[position - 1]) {
+            position -= 1;
+        }
+        while position > 0 && is_word(self.chars[position - 1]) {
+            position -= 1;
+        }
+        position
+    }
+
+    /// Position of the end of the word after the cursor
+    fn word_end(&self, is_word: fn(char) -> bool) -> usize {
+        let mut position = self.cursor;
+        while position < self.chars.len() && !is_word(self.chars[position]) {
+            position += 1;
+        }
+        while position < self.chars.len() && is_word(self.chars[position]) {
+            position += 1;
+        }
+        position
+    }
+
+    pub fn move_word_left(&mut self) {
+        self.cursor = self.word_start(is_word_character);
+    }
+
+    pub fn move_word_right(&mut self) {
+        self.cursor = self.word_end(is_word_character);
+    }
+
+    /// Removes the characters between the two positions, leaving the cursor at the first one,
+    /// and returns them.
+    fn kill(&mut self, start: usize, end: usize) -> String {
+        self.cursor = start;
+        self.chars.drain(start..end).collect()
+    }
+
+    pub fn kill_to_end(&mut self) -> String {
+        let (start, end) = (self.cursor, self.chars.len());
+        self.kill(start, end)
+    }
+
+    pub fn kill_to_start(&mut self) -> String {
+        let end = self.cursor;
+        self.kill(0, end)
+    }
+
+    /// Kills the whitespace delimited word before the cursor, like Ctrl-W in a terminal.
+    pub fn kill_argument_before(&mut self) -> String {
+        let (start, end) = (self.word_start(is_not_whitespace), self.cursor);
+        self.kill(start, end)
+    }
+
+    pub fn kill_word_before(&mut self) -> String {
+        let (start, end) = (self.word_start(is_word_character), self.cursor);
+        self.kill(start, end)
+    }
+
+    pub fn kill_word_after(&mut self) -> String {
+        let (start, end) = (self.cursor, self.word_end(is_word_character));
+        self.kill(start, end)
+    }
+
+    /// Swaps the character before the cursor with the one under it, or the last two characters
+    /// when the cursor is at the end of the line.
+    pub fn transpose(&mut self) {
+        if self.cursor == self.chars.len() && self.cursor >= 2 {
+            self.chars.swap(self.cursor - 2, self.cursor - 1);
+        } else if self.cursor > 0 && self.cursor < self.chars.len() {
+            self.chars.swap(self.cursor - 1, self.cursor);
+            self.cursor += 1;
+        }
+    }
+
+    /// Replaces the whole text, leaving the cursor at the given character position.
+    pub fn replace(&mut self, text: &str, cursor: usize) {
+        self.chars = text.chars().collect();
+        self.cursor = cmp::min(cursor, self.chars.len());
+    }
+
+    pub fn text_before_cursor(&self) -> String {
+        self.chars[..self.cursor].iter().cloned().collect()
+    }
+
+    /// Replaces the text between the given position and the cursor.
+    pub fn replace_before_cursor(&mut self, start: usize, text: &str) {
+        let end = self.cursor;
+        self.kill(start, end);
+        self.insert(text);
+    }
+
+    /// Number of characters between the cursor and the end of the line.
+    pub fn chars_after_cursor(&self) -> usize {
+        self.chars.len() - self.cursor
+    }
+}
+
+impl ToString for LineBuffer {
+    fn to_string(&self) -> String {
+        self.chars.iter().cloned().collect()
+    }
+}
+
+/// Puts the terminal in raw mode for as long as it is alive, so that keys are received as they
+/// are pressed instead of line by line.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::tcgetattr(0, &mut termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = termios;
+            termios.c_iflag &= !(libc::ICRNL | libc::IXON | libc::BRKINT | libc::INPCK | libc::ISTRIP);
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(0, libc::TCSADRAIN, &termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original: original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(0, libc::TCSADRAIN, &self.original); }
+    }
+}
+
+/// What the editor needs from the shell to recall and complete commands.
+pub trait Context {
+    /// The commands entered in this session, the most recent first
+    fn history(&self) -> &VecDeque<String>;
+
+    /// The file holding the commands of previous sessions, one per line
+    fn history_file(&self) -> Option<String>;
+
+    /// Returns where the word that ends at the end of `line`, the text before the cursor, starts
+    /// and the words it could be completed to.
+    fn complete(&mut self, line: &str) -> (usize, Vec<String>);
+}
+
+/// Every command of this session and of the history file, the most recent first and without
+/// duplicates.
+fn search_entries(history: &VecDeque<String>, history_file: Option<String>) -> Vec<String> {
+    let mut entries: Vec<String> = history.iter().cloned().collect();
+    if let Some(path) = history_file {
+        let mut contents = String::new();
+        if let Ok(mut file) = File::open(path) {
+            if file.read_to_string(&mut contents).is_ok() {
+                entries.extend(contents.lines().rev().map(|line| line.to_string()));
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    entries.retain(|entry| !entry.is_empty() && seen.insert(entry.clone()));
+    entries
+}
+
+/// The longest prefix shared by all of the candidates.
+fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix = match candidates.first() {
+        Some(first) => first.clone(),
+        None => return String::new(),
+    };
+    for candidate in &candidates[1..] {
+        let length = prefix.chars().zip(candidate.chars()).take_while(|&(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum();
+        prefix.truncate(length);
+    }
+    prefix
+}
+
+/// The width of the terminal in columns, or 80 if it cannot be determined.
+fn terminal_width() -> usize {
+    unsafe {
+        let mut size: libc::winsize = mem::zeroed();
+        if libc::ioctl(1, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            size.ws_col as usize
+        } else {
+            80
+        }
+    }
+}
+
+/// Returns the index of the first entry from `start` on that contains the query.
+fn search_history(entries: &[String], query: &str, start: usize) -> Option<usize> {
+    entries.iter().enumerate().skip(start).find(|&(_, entry)| entry.contains(query)).map(|(index, _)| index)
+}
+
+/// Reads lines from the terminal with emacs style editing.
+pub struct LineEditor {
+    /// The text removed by the last kill, inserted back by Ctrl-Y
+    kill_buffer: String,
+}
+
+impl Default for LineEditor {
+    fn default() -> LineEditor {
+        LineEditor { kill_buffer: String::new() }
+    }
+}
+
+impl LineEditor {
+    /// Prints the prompt and reads a line, without its trailing newline. Returns `None` at the end
+    /// of the input. When stdin or stdout is not a terminal, the line is read without editing.
+    pub fn read_line<C: Context>(&mut self, prompt: &str, context: &mut C) -> Option<String> {
+        print!("{}", prompt);
+        let _ = stdout().flush();
+        let read_plain_line = || readln().map(|line| line.trim_right_matches(&['\r', '\n'][..]).to_string());
+        // Check for a terminal first, as a line read in raw mode would never end
+        if unsafe { libc::isatty(0) != 1 || libc::isatty(1) != 1 } {
+            return read_plain_line();
+        }
+        let raw_mode = match RawMode::enable() {
+            Ok(raw_mode) => raw_mode,
+            Err(_) => return read_plain_line(),
+        };
+
+        let mut input = UnbufferedStdin;
+        let mut line = LineBuffer::default();
+        // The position in the history while browsing it, and the line that was being edited before
+        let mut history_index: Option<usize> = None;
+        let mut edited_line = String::new();
+        // A key that ended a history search and still has to take effect
+        let mut pending_key: Option<Key> = None;
+        loop {
+            let key = match pending_key.take().or_else(|| read_key(&mut input)) {
+                Some(key) => key,
+                None => return None,
+            };
+            match key {
+                Key::Enter => break,
+                Key::Ctrl('d') if line.is_empty() => {
+                    print!("\n");
+                    return None;
+                },
+                Key::Ctrl('c') => {
+                    // Discard the line and start over on a new one
+                    print!("^C\n{}", prompt);
+                    line = LineBuffer::default();
+                    history_index = None;
+                },
+                Key::Ctrl('l') => print!("\x1b[2J\x1b[H{}", prompt),
+                Key::Up | Key::Ctrl('p') => {
+                    let index = history_index.map_or(0, |index| index + 1);
+                    if let Some(command) = context.history().get(index) {
+                        if history_index.is_none() {
+                            edited_line = line.to_string();
+                        }
+                        history_index = Some(index);
+                        line.replace(command, usize::max_value());
+                    }
+                },
+                Key::Down | Key::Ctrl('n') => {
+                    match history_index {
+                        Some(0) => {
+                            history_index = None;
+                            line.replace(&edited_line, usize::max_value());
+                        },
+                        Some(index) => {
+                            history_index = Some(index - 1);
+                            line.replace(&context.history()[index - 1], usize::max_value());
+                        },
+                        None => (),
+                    }
+                },
+                Key::Ctrl('r') => pending_key = LineEditor::search(prompt, context, &mut line, &mut input),
+                Key::Tab => LineEditor::complete(prompt, context, &mut line),
+                Key::Char(character) => line.insert(&character.to_string()),
+                Key::Backspace | Key::Ctrl('h') => line.delete_before(),
+                Key::Delete | Key::Ctrl('d') => line.delete_at(),
+                Key::Left | Key::Ctrl('b') => line.move_left(),
+                Key::Right | Key::Ctrl('f') => line.move_right(),
+                Key::Home | Key::Ctrl('a') => line.move_home(),
+                Key::End | Key::Ctrl('e') => line.move_end(),
+                Key::WordLeft => line.move_word_left(),
+                Key::WordRight => line.move_word_right(),
+                Key::Ctrl('k') => self.kill_buffer = line.kill_to_end(),
+                Key::Ctrl('u') => self.kill_buffer = line.kill_to_start(),
+                Key::Ctrl('w') => self.kill_buffer = line.kill_argument_before(),
+                Key::Alt('\x7f') => self.kill_buffer = line.kill_word_before(),
+                Key::Alt('d') => self.kill_buffer = line.kill_word_after(),
+                Key::Ctrl('y') => line.insert(&self.kill_buffer),
+                Key::Ctrl('t') => line.transpose(),
+                _ => (),
+            }
+            LineEditor::redraw(prompt, &line);
+        }
+        print!("\n");
+        let _ = stdout().flush();
+        drop(raw_mode);
+        Some(line.to_string())
+    }
+
+    /// Searches the history for commands containing the typed text, starting from the most
+    /// recent one. Ctrl-R moves on to older matches and Ctrl-G or Ctrl-C cancel the search. Any
+    /// other key puts the match in the line and is returned so that it still takes effect.
+    fn search<C: Context, R: Read>(prompt: &str, context: &C, line: &mut LineBuffer, input: &mut R) -> Option<Key> {
+        let entries = search_entries(context.history(), context.history_file());
+        let mut query = String::new();
+        let mut found: Option<usize> = None;
+        let mut failed = false;
+        loop {
+            LineEditor::draw_search(&query, found.map(|index| entries[index].as_str()), failed);
+            let start = match read_key(input) {
+                Some(Key::Char(character)) => {
+                    query.push(character);
+                    // The current match may still contain the longer query
+                    found.unwrap_or(0)
+                },
+                Some(Key::Backspace) => {
+                    query.pop();
+                    0
+                },
+                Some(Key::Ctrl('r')) => found.map_or(0, |index| index + 1),
+                Some(Key::Ctrl('g')) | Some(Key::Ctrl('c')) | None => {
+                    LineEditor::redraw(prompt, line);
+                    return None;
+                },
+                key => {
+                    if let Some(index) = found {
+                        let entry = &entries[index];
+                        let cursor = entry.rfind(query.as_str()).map_or(0, |start| entry[..start].chars().count());
+                        line.replace(entry, cursor);
+                    }
+                    LineEditor::redraw(prompt, line);
+                    return key;
+                },
+            };
+            if query.is_empty() {
+                found = None;
+                failed = false;
+            } else {
+                match search_history(&entries, &query, start) {
+                    Some(index) => {
+                        found = Some(index);
+                        failed = false;
+                    },
+                    None => failed = true,
+                }
+            }
+        }
+    }
+
+    /// Completes the word before the cursor. A single candidate replaces the word, followed by a
+    /// space unless it is a directory. Several candidates are narrowed down to their common
+    /// prefix, or listed below the line when there is none to add.
+    fn complete<C: Context>(prompt: &str, context: &mut C, line: &mut LineBuffer) {
+        let before = line.text_before_cursor();
+        let (start, candidates) = context.complete(&before);
+        let start_position = before[..start].chars().count();
+        match candidates.len() {
+            0 => print!("\x07"),
+            1 => {
+                let mut completion = candidates[0].clone();
+                if !completion.ends_with('/') {
+                    completion.push(' ');
+                }
+                line.replace_before_cursor(start_position, &completion);
+            },
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.len() > before.len() - start {
+                    line.replace_before_cursor(start_position, &prefix);
+                } else {
+                    LineEditor::list_candidates(&candidates);
+                    print!("{}", prompt);
+                }
+            },
+        }
+    }
+
+    /// Prints the candidates below the line in as many columns as fit in the terminal.
+    fn list_candidates(candidates: &[String]) {
+        let names: Vec<&str> = candidates.iter().map(|candidate| candidate_name(candidate)).collect();
+        let width = names.iter().map(|name| name.chars().count()).max().unwrap_or(0) + 2;
+        let columns = cmp::max(1, terminal_width() / width);
+        print!("\n");
+        for (index, name) in names.iter().enumerate() {
+            if (index + 1) % columns == 0 || index + 1 == names.len() {
+                print!("{}\n", name);
+            } else {
+                print!("{:<1$}", name, width);
+            }
+        }
+    }
+
+    /// Draws the search prompt and the current match, with the matched text in reverse video and
+    /// the cursor at its start.
+    fn draw_search(query: &str, found: Option<&str>, failed: bool) {
+        let label = if failed { "failed reverse-i-search" } else { "reverse-i-search" };
+        print!("\r({})`{}': ", label, query);
+        let mut after = 0;
+        if let Some(entry) = found {
+            match entry.rfind(query) {
+                Some(start) if !query.is_empty() => {
+                    let end = start + query.len();
+                    print!("{}\x1b[7m{}\x1b[0m{}", &entry[..start], &entry[start..end], &entry[end..]);
+                    after = entry[start..].chars().count();
+                },
+                _ => print!("{}", entry),
+            }
+        }
+        print!("\x1b[K");
+        if after > 0 {
+            print!("\x1b[{}D", after);
+        }
+        let _ = stdout().flush();
+    }
+
+    /// Redraws the last line of the prompt followed by the text and puts the cursor back in place.
+    fn redraw(prompt: &str, line: &LineBuffer) {
+        let prompt = prompt.rsplit('\n').next().unwrap_or("");
+        print!("\r{}{}\x1b[K", prompt, line.to_string());
+        let after = line.chars_after_cursor();
+        if after > 0 {
+            print!("\x1b[{}D", after);
+        }
+        let _ = stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(input: &[u8]) -> Vec<Key> {
+        let mut input = input;
+        let mut keys = vec![];
+        while let Some(key) = read_key(&mut input) {
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn decode_keys() {
+        assert_eq!(vec![Key::Char('a'), Key::Char('é'), Key::Ctrl('a'), Key::Backspace, Key::Enter],
+                   keys("aé\x01\x7f\r".as_bytes()));
+    }
+
+    #[test]
+    fn decode_escape_sequences() {
+        assert_eq!(vec![Key::Up, Key::Left, Key::WordLeft, Key::Home, Key::End, Key::Delete, Key::WordRight, Key::Alt('d')],
+                   keys(b"\x1b[A\x1b[D\x1b[1;5D\x1b[H\x1bOF\x1b[3~\x1bf\x1bd"));
+    }
+
+    #[test]
+    fn insert_and_move() {
+        let mut line = LineBuffer::default();
+        line.insert("echo world");
+        line.move_word_left();
+        line.insert("hello ");
+        assert_eq!("echo hello world", line.to_string());
+        line.move_home();
+        line.move_word_right();
+        line.delete_at();
+        assert_eq!("echohello world", line.to_string());
+        line.delete_before();
+        line.move_end();
+        line.insert("!");
+        assert_eq!("echhello world!", line.to_string());
+    }
+
+    #[test]
+    fn kill_words() {
+        let mut line = LineBuffer::default();
+        line.insert("cat some/file.txt more");
+        assert_eq!("more", line.kill_argument_before());
+        assert_eq!("txt ", line.kill_word_before());
+        assert_eq!("cat some/file.", line.to_string());
+        line.move_home();
+        assert_eq!("cat", line.kill_word_after());
+        assert_eq!(" some/file.", line.kill_to_end());
+        assert_eq!("", line.to_string());
+    }
+
+    #[test]
+    fn search_entries_are_unique_and_most_recent_first() {
+        let history: VecDeque<String> = vec!["ls", "cd src", "ls", ""].into_iter().map(String::from).collect();
+        assert_eq!(vec!["ls".to_string(), "cd src".to_string()], search_entries(&history, None));
+    }
+
+    #[test]
+    fn search_older_matches() {
+        let entries: Vec<String> = vec!["cargo test", "ls", "cargo build", "cat"].into_iter().map(String::from).collect();
+        assert_eq!(Some(0), search_history(&entries, "ca", 0));
+        assert_eq!(Some(2), search_history(&entries, "ca", 1));
+        assert_eq!(Some(3), search_history(&entries, "ca", 3));
+        assert_eq!(None, search_history(&entries, "rm", 0));
+    }
+
+    #[test]
+    fn complete_to_common_prefix() {
+        let candidates = vec!["src/main.rs".to_string(), "src/mod.rs".to_string()];
+        assert_eq!("src/m", common_prefix(&candidates));
+        assert_eq!("main.rs", candidate_name(&candidates[0]));
+        assert_eq!("src/", candidate_name("src/"));
+
+        let mut line = LineBuffer::default();
+        line.insert("cat sr | wc");
+        line.move_home();
+        line.move_word_right();
+        line.move_word_right();
+        line.replace_before_cursor(4, "src/");
+        assert_eq!("cat src/ | wc", line.to_string());
+    }
+
+    #[test]
+    fn transpose_characters() {
+        let mut line = LineBuffer::default();
+        line.insert("sl");
+        line.transpose();
+        assert_eq!("ls", line.to_string());
     }
 }
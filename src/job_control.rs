@@ -0,0 +1,479 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use libc::{self, c_int, pid_t};
+
+use super::diagnostics::error;
+use super::signals;
+use super::status::{SUCCESS, FAILURE, NO_SUCH_COMMAND};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done(i32),
+}
+
+/// A change in the state of a single process, as reported by `waitpid`.
+enum ProcessEvent {
+    Exited(i32),
+    /// Ended by the signal
+    Signaled(c_int),
+    /// Stopped by the signal
+    Stopped(c_int),
+    /// Continued by SIGCONT
+    Continued,
+}
+
+/// A pipeline that was sent to the background with `&` or stopped while in the foreground,
+/// identified by its job number.
+pub struct BackgroundJob {
+    pub id: usize,
+    pub command: String,
+    pub state: JobState,
+    /// The process group of the pipeline, or 0 if it stayed in the shell's group
+    pgid: pid_t,
+    /// The pids of every process in the pipeline, along with their exit status once they end.
+    /// A command that could not be started has no pid and the status `NO_SUCH_COMMAND`.
+    processes: Vec<(pid_t, Option<i32>)>,
+    /// The signal that stopped the job, or that ended its last process
+    signal: Option<c_int>,
+    /// Whether the job has the status of its last failing process, as with `set -o pipefail`
+    pipefail: bool,
+}
+
+impl BackgroundJob {
+    fn new(id: usize, pids: Vec<Option<pid_t>>, pgid: pid_t, command: String, pipefail: bool) -> BackgroundJob {
+        let processes: Vec<(pid_t, Option<i32>)> = pids.into_iter().map(|pid| match pid {
+            Some(pid) => (pid, None),
+            None => (0, Some(NO_SUCH_COMMAND)),
+        }).collect();
+        let state = if processes.iter().all(|&(_, status)| status.is_some()) {
+            JobState::Done(NO_SUCH_COMMAND)
+        } else {
+            JobState::Running
+        };
+        BackgroundJob {
+            id: id,
+            command: command,
+            state: state,
+            pgid: pgid,
+            processes: processes,
+            signal: None,
+            pipefail: pipefail,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        match self.state {
+            JobState::Done(_) => true,
+            _ => false,
+        }
+    }
+
+    fn live_pids(&self) -> Vec<pid_t> {
+        self.processes.iter().filter(|&&(_, status)| status.is_none()).map(|&(pid, _)| pid).collect()
+    }
+
+    fn update(&mut self, pid: pid_t, event: ProcessEvent) {
+        let code = match event {
+            ProcessEvent::Exited(code) => code,
+            ProcessEvent::Signaled(signal) => {
+                if self.processes.last().map_or(false, |&(last, _)| last == pid) {
+                    self.signal = Some(signal);
+                }
+                signals::status(signal)
+            },
+            ProcessEvent::Stopped(signal) => {
+                self.signal = Some(signal);
+                self.state = JobState::Stopped;
+                return;
+            },
+            ProcessEvent::Continued => {
+                if self.state == JobState::Stopped {
+                    self.signal = None;
+                    self.state = JobState::Running;
+                }
+                return;
+            },
+        };
+        if let Some(process) = self.processes.iter_mut().find(|process| process.0 == pid) {
+            process.1 = Some(code);
+        }
+        if self.processes.iter().all(|&(_, status)| status.is_some()) {
+            // Like a foreground pipeline, the job reports the status of its last process, or of
+            // its last failing one with pipefail
+            let code = if self.pipefail {
+                self.processes.iter().rev().filter_map(|&(_, status)| status).find(|&status| status != SUCCESS)
+            } else {
+                self.processes.last().and_then(|&(_, status)| status)
+            };
+            let code = code.unwrap_or(SUCCESS);
+            self.state = JobState::Done(code);
+        }
+    }
+
+    /// Sends SIGCONT to the process group of the job, or to each of its processes that is still
+    /// alive if it has no group of its own.
+    fn resume(&mut self) {
+        if self.pgid > 0 {
+            unsafe { libc::kill(-self.pgid, libc::SIGCONT); }
+        } else {
+            for pid in self.live_pids() {
+                unsafe { libc::kill(pid, libc::SIGCONT); }
+            }
+        }
+        self.signal = None;
+        self.state = JobState::Running;
+    }
+
+    fn print(&self) {
+        let state = match (self.state, self.signal) {
+            (JobState::Running, _) => "Running".to_string(),
+            (JobState::Stopped, _) => "Stopped".to_string(),
+            (JobState::Done(_), Some(signal)) => signals::name(signal),
+            (JobState::Done(SUCCESS), None) => "Done".to_string(),
+            (JobState::Done(code), None) => format!("Exit {}", code),
+        };
+        println!("[{}] {:<10} {}", self.id, state, self.command);
+    }
+}
+
+/// Waits for the process with the given pid to change state. If `block` is false this returns
+/// `None` immediately when the process has not changed state, and also reports a stopped process
+/// that was continued.
+fn wait_pid(pid: pid_t, block: bool) -> Option<ProcessEvent> {
+    let options = if block { libc::WUNTRACED } else { libc::WUNTRACED | libc::WNOHANG | libc::WCONTINUED };
+    let mut status: c_int = 0;
+    loop {
+        let result = unsafe { libc::waitpid(pid, &mut status, options) };
+        if result == 0 {
+            return None;
+        } else if result < 0 {
+            if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            // The process can no longer be waited on, so there is nothing left to track
+            return Some(ProcessEvent::Exited(FAILURE));
+        }
+        return if libc::WIFEXITED(status) {
+            Some(ProcessEvent::Exited(libc::WEXITSTATUS(status)))
+        } else if libc::WIFSIGNALED(status) {
+            Some(ProcessEvent::Signaled(libc::WTERMSIG(status)))
+        } else if libc::WIFSTOPPED(status) {
+            Some(ProcessEvent::Stopped(libc::WSTOPSIG(status)))
+        } else if libc::WIFCONTINUED(status) {
+            Some(ProcessEvent::Continued)
+        } else {
+            None
+        };
+    }
+}
+
+/// The table of background jobs owned by the shell.
+pub struct JobControl {
+    jobs: Vec<BackgroundJob>,
+    /// Processes that were removed from the table with `disown` but still have to be reaped
+    disowned: Vec<pid_t>,
+    /// The terminal of an interactive shell, which is handed to each foreground pipeline
+    terminal: Option<RawFd>,
+    /// The process group of the shell, which gets the terminal back after each pipeline
+    pgid: pid_t,
+    /// Whether new jobs have the status of their last failing process, as with `set -o pipefail`
+    pub pipefail: bool,
+}
+
+impl Default for JobControl {
+    fn default() -> JobControl {
+        JobControl {
+            jobs: vec![],
+            disowned: vec![],
+            terminal: None,
+            pgid: 0,
+            pipefail: false,
+        }
+    }
+}
+
+impl JobControl {
+    /// Enables job control if the shell reads from a terminal: the shell ignores the signals sent
+    /// by the terminal and takes it over in a process group of its own, and each pipeline gets a
+    /// process group that owns the terminal while it runs in the foreground.
+    pub fn enable(&mut self) {
+        unsafe {
+            if libc::isatty(0) == 0 {
+                return;
+            }
+            // If the shell was started in the background, wait until it is brought to the foreground
+            loop {
+                let group = libc::getpgrp();
+                if libc::tcgetpgrp(0) == group {
+                    break;
+                }
+                libc::kill(-group, libc::SIGTTIN);
+            }
+            signals::ignore_terminal_signals();
+            // This fails harmlessly when the shell already leads a session
+            libc::setpgid(0, 0);
+            let terminal = libc::fcntl(0, libc::F_DUPFD_CLOEXEC, 10);
+            if terminal < 0 {
+                return;
+            }
+            self.pgid = libc::getpgrp();
+            libc::tcsetpgrp(terminal, self.pgid);
+            self.terminal = Some(terminal);
+        }
+    }
+
+    /// Disables job control in a fork of the shell, whose pipelines stay in its process group.
+    pub fn disable(&mut self) {
+        self.terminal = None;
+    }
+
+    /// The terminal that foreground pipelines are given, if job control is enabled.
+    pub fn terminal(&self) -> Option<RawFd> {
+        self.terminal
+    }
+
+    /// Registers the processes of a pipeline that was started in the background and prints its
+    /// job number along with the pid of its last process.
+    pub fn add(&mut self, pids: Vec<Option<pid_t>>, pgid: pid_t, command: String) -> usize {
+        let id = self.next_id();
+        if let Some(pid) = pids.iter().filter_map(|&pid| pid).last() {
+            println!("[{}] {}", id, pid);
+        }
+        self.jobs.push(BackgroundJob::new(id, pids, pgid, command, self.pipefail));
+        id
+    }
+
+    /// Waits for the processes of a pipeline that was started in the foreground, which has the
+    /// terminal until it ends or is stopped, and returns its exit status. A stopped pipeline is
+    /// kept in the table as a job that `fg` and `bg` can continue.
+    pub fn wait_foreground(&mut self, pids: Vec<Option<pid_t>>, pgid: pid_t, command: String) -> i32 {
+        let id = self.next_id();
+        self.jobs.push(BackgroundJob::new(id, pids, pgid, command, self.pipefail));
+        let index = self.jobs.len() - 1;
+        self.wait_for(index, true)
+    }
+
+    fn next_id(&self) -> usize {
+        self.jobs.last().map_or(1, |job| job.id + 1)
+    }
+
+    /// Hands the terminal to the process group, or back to the shell.
+    fn set_foreground(&self, pgid: pid_t) {
+        if let Some(terminal) = self.terminal {
+            if pgid > 0 {
+                unsafe { libc::tcsetpgrp(terminal, pgid); }
+            }
+        }
+    }
+
+    /// Collects the status of every background process that changed state, without blocking.
+    /// Stopped jobs are polled too, as they may be killed or continued from outside the shell.
+    fn reap(&mut self) {
+        for job in self.jobs.iter_mut().filter(|job| !job.is_finished()) {
+            for pid in job.live_pids() {
+                if let Some(event) = wait_pid(pid, false) {
+                    job.update(pid, event);
+                }
+            }
+        }
+        self.disowned.retain(|&pid| match wait_pid(pid, false) {
+            Some(ProcessEvent::Exited(_)) | Some(ProcessEvent::Signaled(_)) => false,
+            _ => true,
+        });
+    }
+
+    /// Reaps finished background jobs, printing a notice for each one and removing it from the
+    /// table. This is called before every prompt.
+    pub fn report_finished(&mut self) {
+        self.reap();
+        for job in self.jobs.iter().filter(|job| job.is_finished()) {
+            job.print();
+        }
+        self.remove_finished();
+    }
+
+    fn remove_finished(&mut self) {
+        self.jobs.retain(|job| !job.is_finished());
+    }
+
+    /// Finds the index of the job referred to by `%N` or `N`, or of the most recent job if no job
+    /// was specified. Errors are reported as coming from the builtin `command`.
+    fn find_job(&self, command: &str, spec: Option<&str>) -> Option<usize> {
+        match spec {
+            Some(spec) => {
+                let spec = spec.trim_left_matches('%');
+                if let Ok(id) = spec.parse::<usize>() {
+                    if let Some(index) = self.jobs.iter().position(|job| job.id == id) {
+                        return Some(index);
+                    }
+                }
+                error(command, format!("no such job: {}", spec));
+                None
+            },
+            None => {
+                if self.jobs.is_empty() {
+                    error(command, "no current job");
+                    None
+                } else {
+                    Some(self.jobs.len() - 1)
+                }
+            }
+        }
+    }
+
+    /// Blocks until the job at the given index finishes or is stopped and returns its exit status,
+    /// which is `128+N` if signal N ended its last process or stopped it. Finished jobs are
+    /// removed from the table. A job waited for in the foreground has the terminal meanwhile, and
+    /// a signal that ended it is reported.
+    fn wait_for(&mut self, index: usize, foreground: bool) -> i32 {
+        if foreground {
+            self.set_foreground(self.jobs[index].pgid);
+        }
+        while self.jobs[index].state == JobState::Running {
+            let pid = match self.jobs[index].live_pids().first() {
+                Some(&pid) => pid,
+                None => break,
+            };
+            if let Some(event) = wait_pid(pid, true) {
+                self.jobs[index].update(pid, event);
+            }
+        }
+        if foreground {
+            self.set_foreground(self.pgid);
+        }
+        match self.jobs[index].state {
+            JobState::Done(code) => {
+                let job = self.jobs.remove(index);
+                match job.signal {
+                    // The terminal shows the ^C, so only start a new line after it
+                    Some(libc::SIGINT) if foreground && self.terminal.is_some() => println!(""),
+                    // A reader that stopped reading, like `head`, is not worth reporting
+                    Some(libc::SIGINT) | Some(libc::SIGPIPE) | None => (),
+                    Some(signal) if foreground => {
+                        error(&job.command, format!("terminated by {}", signals::name(signal)));
+                    },
+                    Some(_) => (),
+                }
+                code
+            },
+            _ => {
+                if foreground && self.terminal.is_some() {
+                    println!("");
+                }
+                self.jobs[index].print();
+                signals::status(self.jobs[index].signal.unwrap_or(libc::SIGTSTP))
+            }
+        }
+    }
+
+    /// Display the jobs currently in the table.
+    pub fn jobs<I: IntoIterator>(&mut self, _: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        self.reap();
+        for job in &self.jobs {
+            job.print();
+        }
+        self.remove_finished();
+        SUCCESS
+    }
+
+    /// Continues a job in the foreground and waits for it.
+    pub fn fg<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let spec = args.into_iter().nth(1);
+        match self.find_job("fg", spec.as_ref().map(|spec| spec.as_ref())) {
+            Some(index) => {
+                println!("{}", self.jobs[index].command);
+                if self.jobs[index].state == JobState::Stopped {
+                    self.jobs[index].resume();
+                }
+                self.wait_for(index, true)
+            },
+            None => FAILURE
+        }
+    }
+
+    /// Continues a stopped job in the background.
+    pub fn bg<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let spec = args.into_iter().nth(1);
+        match self.find_job("bg", spec.as_ref().map(|spec| spec.as_ref())) {
+            Some(index) => {
+                let job = &mut self.jobs[index];
+                if job.state == JobState::Stopped {
+                    job.resume();
+                    println!("[{}] {} &", job.id, job.command);
+                    SUCCESS
+                } else {
+                    error("bg", format!("job {} is already running in the background", job.id));
+                    FAILURE
+                }
+            },
+            None => FAILURE
+        }
+    }
+
+    /// Waits for the given jobs, or every job if none are given, and returns the exit status of
+    /// the last one.
+    pub fn wait<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let specs: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+        let mut status = SUCCESS;
+        if specs.is_empty() {
+            let mut index = 0;
+            while index < self.jobs.len() {
+                if self.jobs[index].state == JobState::Stopped {
+                    // A stopped job will not finish on its own, so skip over it
+                    index += 1;
+                } else {
+                    status = self.wait_for(index, false);
+                }
+            }
+        } else {
+            for spec in &specs {
+                status = match self.find_job("wait", Some(spec)) {
+                    Some(index) => self.wait_for(index, false),
+                    None => FAILURE,
+                };
+            }
+        }
+        status
+    }
+
+    /// Removes jobs from the table without terminating them.
+    pub fn disown<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let specs: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+        let mut indices: Vec<usize> = if specs.iter().any(|spec| spec == "-a") {
+            (0..self.jobs.len()).collect()
+        } else if specs.is_empty() {
+            match self.find_job("disown", None) {
+                Some(index) => vec![index],
+                None => return FAILURE,
+            }
+        } else {
+            let mut indices = vec![];
+            for spec in &specs {
+                match self.find_job("disown", Some(spec)) {
+                    Some(index) => indices.push(index),
+                    None => return FAILURE,
+                }
+            }
+            indices
+        };
+        indices.sort();
+        indices.dedup();
+        for index in indices.into_iter().rev() {
+            let job = self.jobs.remove(index);
+            self.disowned.extend(job.live_pids());
+        }
+        SUCCESS
+    }
+}
@@ -4,34 +4,57 @@
 #![plugin(peg_syntax_ext)]
 
 extern crate glob;
+extern crate libc;
 extern crate regex;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
-use std::io::{stdout, Read, Write};
+use std::io::{self, Read, Write};
 use std::env;
 use std::process;
+use std::mem;
+
+use glob::{glob, Pattern};
 
 use self::directory_stack::DirectoryStack;
-use self::input_editor::readln;
-use self::peg::{parse, Pipeline};
+use self::input_editor::{LineEditor, Context as EditorContext};
+use self::completion::{current_word, complete_names, complete_variable, complete_path, executables, Completions};
+use self::peg::{parse, Pipeline, Job, Redirection, RedirectionTarget};
 use self::variables::Variables;
 use self::history::History;
-use self::flow_control::{FlowControl, is_flow_control_command, Statement};
-use self::status::{SUCCESS, NO_SUCH_COMMAND};
+use self::flow_control::{FlowControl, is_block_command, is_comparison, test, Statement, LoopControl};
+use self::status::{SUCCESS, FAILURE, NO_SUCH_COMMAND, SYNTAX_ERROR};
 use self::function::Function;
-use self::pipe::execute_pipeline;
+use self::pipe::{spawn_pipeline, finish_pipeline, capture_stdout, with_redirections};
+use self::job_control::JobControl;
+use self::substitution::{split_substitutions, Segment};
+use self::arithmetic::{evaluate, math};
+use self::quoting::{split_quotes, split_here_document, is_glob, escape, Quoting};
+use self::diagnostics::error;
+use self::traps::{Traps, Event};
+use self::options::Options;
+use self::alias::Aliases;
 
 pub mod pipe;
 pub mod directory_stack;
-pub mod to_num;
 pub mod input_editor;
+pub mod arithmetic;
+pub mod quoting;
+pub mod here_document;
 pub mod peg;
 pub mod variables;
 pub mod history;
 pub mod flow_control;
 pub mod status;
 pub mod function;
+pub mod job_control;
+pub mod substitution;
+pub mod completion;
+pub mod diagnostics;
+pub mod signals;
+pub mod traps;
+pub mod options;
+pub mod alias;
 
 /// This struct will contain all of the data structures related to this
 /// instance of the shell.
@@ -40,65 +63,157 @@ pub struct Shell {
     flow_control: FlowControl,
     directory_stack: DirectoryStack,
     history: History,
-    functions: HashMap<String, Function>
+    functions: HashMap<String, Function>,
+    job_control: JobControl,
+    completions: Completions,
+    traps: Traps,
+    options: Options,
+    aliases: Aliases,
 }
 
 impl Default for Shell {
     /// Panics if DirectoryStack construction fails
     fn default() -> Shell {
+        let mut new_shell = Shell::new();
+        new_shell.evaluate_init_file();
+        new_shell
+    }
+}
+
+impl Shell {
+    /// Creates a shell with the default variables, without evaluating the init file. Panics if
+    /// DirectoryStack construction fails
+    fn new() -> Shell {
         let mut new_shell = Shell {
             variables: Variables::default(),
             flow_control: FlowControl::default(),
             directory_stack: DirectoryStack::new().expect(""),
             history: History::default(),
-            functions: HashMap::new()
+            functions: HashMap::new(),
+            job_control: JobControl::default(),
+            completions: Completions::default(),
+            traps: Traps::default(),
+            options: Options::default(),
+            aliases: Aliases::default(),
         };
         new_shell.initialize_default_variables();
-        new_shell.evaluate_init_file();
         new_shell
     }
-}
 
-impl Shell {
     fn execute(&mut self) {
         let commands = Command::map();
-        let mut dash_c = false;
-        for arg in env::args().skip(1) {
-            if arg == "-c" {
-                dash_c = true;
+        let mut args = env::args();
+        let shell_name = args.next().unwrap_or("ion".to_string());
+        let args: Vec<String> = args.collect();
+
+        if let Some(first) = args.first() {
+            if first == "-c" {
+                // The arguments after the command start at `$0`, as in `ion -c 'echo $1' ion foo`
+                match args.get(1) {
+                    Some(command) => {
+                        let positional = if args.len() > 2 { args[2..].to_vec() } else { vec![shell_name] };
+                        self.variables.set_args(positional);
+                        self.on_command(command, "-c", &commands);
+                    },
+                    None => {
+                        error("-c", "option requires an argument");
+                        process::exit(status::FAILURE);
+                    }
+                }
             } else {
-                if dash_c {
-                    self.on_command(&arg, &commands);
-                } else {
-                    match File::open(&arg) {
-                        Ok(mut file) => {
-                            let mut command_list = String::new();
-                            match file.read_to_string(&mut command_list) {
-                                Ok(_) => self.on_command(&command_list, &commands),
-                                Err(err) => println!("ion: failed to read {}: {}", arg, err)
+                // The script is `$0` and the arguments after it are `$1` and on
+                self.variables.set_args(args.clone());
+                match File::open(first) {
+                    Ok(mut file) => {
+                        let mut command_list = String::new();
+                        match file.read_to_string(&mut command_list) {
+                            Ok(_) => {
+                                self.on_command(&command_list, first, &commands);
+                            },
+                            Err(err) => {
+                                error(first, err);
+                                self.variables.set_var("?", &FAILURE.to_string());
+                                self.history.previous_status = FAILURE;
                             }
-                        },
-                        Err(err) => println!("ion: failed to open {}: {}", arg, err)
+                        }
+                    },
+                    Err(err) => {
+                        // The script is missing like a command that is not found
+                        error(first, err);
+                        self.variables.set_var("?", &NO_SUCH_COMMAND.to_string());
+                        self.history.previous_status = NO_SUCH_COMMAND;
                     }
                 }
-
-                // Exit with the previous command's exit status.
-                process::exit(self.history.previous_status);
             }
+
+            // Exit with the previous command's exit status.
+            let status = self.history.previous_status;
+            self.exit(status, &commands);
         }
 
-        self.print_prompt();
-        while let Some(command) = readln() {
+        self.variables.set_args(vec![shell_name]);
+        self.job_control.enable();
+        let mut line_editor = LineEditor::default();
+        loop {
+            let prompt = self.prompt();
+            let mut command = match line_editor.read_line(&prompt, self) {
+                Some(command) => command,
+                None => break,
+            };
+            // The body of a here-document follows on the next lines, up to its delimiter
+            while here_document::is_unterminated(&command) {
+                match line_editor.read_line("> ", self) {
+                    Some(line) => {
+                        command.push('\n');
+                        command.push_str(&line);
+                    },
+                    None => break,
+                }
+            }
             let command = command.trim();
             if !command.is_empty() {
-                self.on_command(command, &commands);
+                self.on_command(command, "stdin", &commands);
             }
             self.update_variables();
-            self.print_prompt();
+            self.job_control.report_finished();
         }
 
         // Exit with the previous command's exit status.
-        process::exit(self.history.previous_status);
+        let status = self.history.previous_status;
+        self.exit(status, &commands);
+    }
+
+    /// Runs the `EXIT` trap and exits with the status.
+    fn exit(&mut self, status: i32, commands: &HashMap<&str, Command>) -> ! {
+        self.run_trap(Event::Exit, commands);
+        process::exit(status);
+    }
+
+    /// Runs the code trapped for the event, if there is any, without changing `$?`. No trap is run
+    /// from the code of another.
+    fn run_trap(&mut self, event: Event, commands: &HashMap<&str, Command>) {
+        if self.traps.running {
+            return;
+        }
+        if let Some(action) = self.traps.action(event) {
+            let previous_status = self.history.previous_status;
+            self.traps.running = true;
+            // The code of a trap was not entered by the user, so it is not added to the history
+            self.execute_code(&action, "trap", commands);
+            self.traps.running = false;
+            self.variables.set_var("?", &previous_status.to_string());
+            self.history.previous_status = previous_status;
+        }
+    }
+
+    /// Runs the traps of the signals that were received since they were last run.
+    fn run_pending_traps(&mut self, commands: &HashMap<&str, Command>) {
+        if self.traps.running {
+            return;
+        }
+        for signal in signals::take_pending() {
+            self.run_trap(Event::Signal(signal), commands);
+        }
     }
 
     /// This function will initialize the default variables used by the shell. This function will
@@ -162,157 +277,547 @@ impl Shell {
             if let Ok(mut file) = File::open(source_file.clone()) {
                 let mut command_list = String::new();
                 if let Err(message) = file.read_to_string(&mut command_list) {
-                    println!("{}: Failed to read {:?}", message, source_file.clone());
+                    error(&source_file.to_string_lossy(), message);
                 } else {
-                    self.on_command(&command_list, commands);
+                    self.on_command(&command_list, &source_file.to_string_lossy(), commands);
                 }
             } else {
-                if let Err(message) = File::create(source_file) {
-                    println!("{}", message);
+                if let Err(message) = File::create(&source_file) {
+                    error(&source_file.to_string_lossy(), message);
                 }
             }
         } else {
-            println!("ion: could not get home directory");
+            error(".ionrc", "could not get home directory");
         }
     }
 
-    pub fn print_prompt(&self) {
-        self.print_prompt_prefix();
+    /// Builds the prompt shown before reading each line.
+    pub fn prompt(&self) -> String {
+        let mut prompt = self.prompt_prefix();
         match self.flow_control.current_statement {
-            Statement::For(_, _) => self.print_for_prompt(),
-            Statement::Function(_, _) => self.print_function_prompt(),
-            _ => self.print_default_prompt(),
+            Statement::For(_, _) => prompt.push_str(&self.block_prompt("for")),
+            Statement::While(_) => prompt.push_str(&self.block_prompt("while")),
+            Statement::Function(_, _) => prompt.push_str(&self.block_prompt("fn")),
+            _ => prompt.push_str(&self.default_prompt()),
         }
-        if let Err(message) = stdout().flush() {
-            println!("{}: failed to flush prompt to stdout", message);
-        }
-
+        prompt
     }
 
     // TODO eventually this thing should be gone
-    fn print_prompt_prefix(&self) {
-        let prompt_prefix = self.flow_control.modes.iter().rev().fold(String::new(), |acc, mode| {
+    fn prompt_prefix(&self) -> String {
+        self.flow_control.modes.iter().rev().fold(String::new(), |acc, mode| {
             acc +
             if mode.value {
                 "+ "
             } else {
                 "- "
             }
-        });
-        print!("{}", prompt_prefix);
+        })
     }
 
-    fn print_for_prompt(&self) {
-        print!("for> ");
-    }
-
-    fn print_function_prompt(&self) {
-        print!("fn> ");
+    /// The keyword of every block still being collected, outermost first, e.g. `for if> `.
+    fn block_prompt(&self, keyword: &str) -> String {
+        let mut prompt = keyword.to_string();
+        for block in &self.flow_control.nested_blocks {
+            prompt.push(' ');
+            prompt.push_str(block);
+        }
+        prompt + "> "
     }
 
-    fn print_default_prompt(&self) {
-        print!("{}", self.variables.expand_string(&self.variables.expand_string("$PROMPT")));
+    fn default_prompt(&self) -> String {
+        self.variables.expand_string(&self.variables.expand_string("$PROMPT"))
     }
 
-    fn on_command(&mut self, command_string: &str, commands: &HashMap<&str, Command>) {
+    /// Adds the code, which came from the file `source`, to the history and executes it like
+    /// `execute_code`.
+    fn on_command(&mut self, command_string: &str, source: &str, commands: &HashMap<&str, Command>) -> bool {
         self.history.add(command_string.to_string(), &self.variables);
+        self.execute_code(command_string, source, commands)
+    }
 
-        let mut pipelines = parse(command_string);
+    /// Parses and executes the code, which came from the file `source`. Returns false if it has a
+    /// syntax error, which is reported instead, and then none of it is executed.
+    fn execute_code(&mut self, code: &str, source: &str, commands: &HashMap<&str, Command>) -> bool {
+        match parse(code) {
+            Ok(pipelines) => {
+                self.execute_pipelines(pipelines, commands);
+                true
+            },
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "{}", err.report(source));
+                self.variables.set_var("?", &SYNTAX_ERROR.to_string());
+                self.history.previous_status = SYNTAX_ERROR;
+                false
+            }
+        }
+    }
 
-        // Execute commands
+    /// Executes each pipeline in turn, collecting the pipelines of blocks until their `end`.
+    fn execute_pipelines(&mut self, mut pipelines: Vec<Pipeline>, commands: &HashMap<&str, Command>) {
         for pipeline in pipelines.drain(..) {
+            if self.flow_control.loop_control.is_some() {
+                // A break or continue skips the rest of the loop body
+                break;
+            }
+            let command = pipeline.jobs[0].command.clone();
             if self.flow_control.collecting_block {
-                // TODO move this logic into "end" command
-                if pipeline.jobs[0].command == "end" {
-                    self.flow_control.collecting_block = false;
-                    let block_jobs: Vec<Pipeline> = self.flow_control
-                                                   .current_block
-                                                   .pipelines
-                                                   .drain(..)
-                                                   .collect();
-                    match self.flow_control.current_statement.clone() {
-                        Statement::For(ref var, ref vals) => {
-                            let variable = var.clone();
-                            let values = vals.clone();
-                            for value in values {
-                                self.variables.set_var(&variable, &value);
-                                for pipeline in &block_jobs {
-                                    self.run_pipeline(&pipeline, commands);
-                                }
-                            }
-                        },
-                        Statement::Function(ref name, ref args) => {
-                            self.functions.insert(name.clone(), Function { name: name.clone(), pipelines: block_jobs.clone(), args: args.clone() });
-                        },
-                        _ => {}
-                    }
-                    self.flow_control.current_statement = Statement::Default;
+                if command == "end" && self.flow_control.nested_blocks.is_empty() {
+                    self.end_block(commands);
                 } else {
+                    if is_block_command(&command) {
+                        self.flow_control.nested_blocks.push(command);
+                    } else if command == "end" {
+                        self.flow_control.nested_blocks.pop();
+                    }
                     self.flow_control.current_block.pipelines.push(pipeline);
                 }
+            } else if command == "else" || command == "elif" {
+                self.else_branch(&pipeline, commands);
+            } else if self.flow_control.skipping() {
+                if is_block_command(&command) {
+                    self.flow_control.skip_block();
+                } else if command == "end" {
+                    self.run_pipeline(&pipeline, commands);
+                }
+            } else if command == "if" {
+                let value = self.evaluate_condition(&pipeline, commands);
+                self.flow_control.if_(value);
+            } else if command == "while" {
+                // The condition is expanded again on every iteration, so keep it unexpanded
+                self.flow_control.while_(pipeline);
             } else {
-                if self.flow_control.skipping() && !is_flow_control_command(&pipeline.jobs[0].command) {
-                    continue;
+                self.traps.error_reported = false;
+                if let Some(status) = self.run_pipeline(&pipeline, commands) {
+                    if status != SUCCESS {
+                        if !self.traps.error_reported && !self.traps.ignore_errors {
+                            self.run_trap(Event::Error, commands);
+                        }
+                        self.traps.error_reported = true;
+                        if self.options.errexit {
+                            self.exit(status, commands);
+                        }
+                    }
                 }
-                self.run_pipeline(&pipeline, commands);
             }
+            self.run_pending_traps(commands);
         }
     }
 
-    fn run_pipeline(&mut self, pipeline: &Pipeline, commands: &HashMap<&str, Command>) -> Option<i32> {
-        let mut pipeline = self.variables.expand_pipeline(pipeline);
-        pipeline.expand_globs();
-        let exit_status = if let Some(command) = commands.get(pipeline.jobs[0].command.as_str()) {
-            Some((*command.main)(pipeline.jobs[0].args.as_slice(), self))
-        } else if let Some(function) = self.functions.get(pipeline.jobs[0].command.as_str()).cloned() {
-            if pipeline.jobs[0].args.len() - 1 == function.args.len() {
-                let mut variables_backup: HashMap<&str, Option<String>> = HashMap::new();
-                for (name, value) in function.args.iter().zip(pipeline.jobs[0].args.iter().skip(1)) {
-                    variables_backup.insert(name, self.variables.get_var(name));
-                    self.variables.set_var(name, value);
+    /// Switches to the next branch of the innermost if chain. The condition of an `else if` or
+    /// `elif` is only evaluated when no earlier branch of the chain was taken.
+    fn else_branch(&mut self, pipeline: &Pipeline, commands: &HashMap<&str, Command>) {
+        let args = &pipeline.jobs[0].args;
+        if args[0] == "else" && args.get(1).map_or(true, |arg| arg != "if") {
+            self.flow_control.else_(args);
+            return;
+        }
+        let value = if self.flow_control.else_if_pending() {
+            let mut condition = pipeline.clone();
+            if args[0] == "else" {
+                // Evaluate `else if condition` as `if condition`
+                condition.jobs[0] = Job::new(args[1..].to_vec(), pipeline.jobs[0].background);
+            }
+            // The branch that was not taken is left out while the condition runs, otherwise a
+            // function called by the condition would skip its body
+            let branch = self.flow_control.modes.remove(0);
+            let value = self.evaluate_condition(&condition, commands);
+            self.flow_control.modes.insert(0, branch);
+            value
+        } else {
+            false
+        };
+        self.flow_control.else_if(value);
+    }
+
+    /// Executes the block whose `end` was just reached, or stores it if it defines a function.
+    fn end_block(&mut self, commands: &HashMap<&str, Command>) {
+        self.flow_control.collecting_block = false;
+        let block_jobs: Vec<Pipeline> = self.flow_control
+                                       .current_block
+                                       .pipelines
+                                       .drain(..)
+                                       .collect();
+        let statement = mem::replace(&mut self.flow_control.current_statement, Statement::Default);
+        match statement {
+            Statement::For(variable, values) => {
+                self.flow_control.loop_depth += 1;
+                for value in values {
+                    self.variables.set_var(&variable, &value);
+                    if self.run_loop_body(&block_jobs, commands) {
+                        break;
+                    }
                 }
-                let mut return_value = None;
-                for function_pipeline in &function.pipelines {
-                    return_value = self.run_pipeline(function_pipeline, commands)
+                self.flow_control.loop_depth -= 1;
+            },
+            Statement::While(condition) => {
+                self.flow_control.loop_depth += 1;
+                while self.evaluate_condition(&condition, commands) {
+                    if self.run_loop_body(&block_jobs, commands) {
+                        break;
+                    }
                 }
-                for (name, value_option) in &variables_backup {
-                    match *value_option {
-                        Some(ref value) => self.variables.set_var(name, value),
-                        None => {self.variables.unset_var(name);},
+                self.flow_control.loop_depth -= 1;
+            },
+            Statement::Function(name, args) => {
+                self.functions.insert(name.clone(), Function { name: name, pipelines: block_jobs, args: args });
+            },
+            _ => {}
+        }
+    }
+
+    /// Executes one iteration of a loop body and returns true if a `break` ended the loop.
+    fn run_loop_body(&mut self, body: &[Pipeline], commands: &HashMap<&str, Command>) -> bool {
+        let modes = self.flow_control.modes.len();
+        self.execute_pipelines(body.to_vec(), commands);
+        // A break or continue inside an if skips its end, so drop the modes it left open
+        let unclosed = self.flow_control.modes.len() - modes;
+        self.flow_control.modes.drain(..unclosed);
+        self.flow_control.loop_control.take() == Some(LoopControl::Break)
+    }
+
+    /// Evaluates the condition of an `if` or `while` pipeline, whose first word is the keyword.
+    /// The condition is either a comparison like `if $a == b`, or a pipeline whose exit status
+    /// decides, like `if grep -q foo file`. A leading `not` or `!` negates it.
+    fn evaluate_condition(&mut self, pipeline: &Pipeline, commands: &HashMap<&str, Command>) -> bool {
+        let mut words: Vec<String> = pipeline.jobs[0].args.iter().skip(1).cloned().collect();
+        let negate = words.first().map_or(false, |word| word == "not" || word == "!");
+        if negate {
+            words.remove(0);
+        }
+        if words.is_empty() {
+            error(&pipeline.jobs[0].command, "missing condition");
+            return false;
+        }
+        let comparison_form = words.len() == 3 && is_comparison(&words[1]) && pipeline.jobs.len() == 1 &&
+                            pipeline.redirections.is_empty() && pipeline.chain.is_empty();
+        let value = if comparison_form {
+            let mut args = vec!["test".to_string()];
+            for word in &words {
+                match self.expand_word(word, commands) {
+                    Ok(word) => args.push(word),
+                    Err(message) => {
+                        error(&pipeline.jobs[0].command, message);
+                        return false;
                     }
                 }
-                return_value
-            } else {
-                println!("This function takes {} arguments, but you provided {}", function.args.len(), pipeline.jobs[0].args.len()-1);
-                Some(NO_SUCH_COMMAND) // not sure if this is the right error code
             }
+            test(args) == SUCCESS
         } else {
-            Some(execute_pipeline(pipeline))
+            let mut condition = pipeline.clone();
+            condition.jobs[0] = Job::new(words, pipeline.jobs[0].background);
+            self.ignoring_errors(|shell| shell.run_pipeline(&condition, commands));
+            self.history.previous_status == SUCCESS
         };
-        if let Some(code) = exit_status {
-            self.variables.set_var("?", &code.to_string());
-            self.history.previous_status = code;
+        value != negate
+    }
+
+    /// Runs the pipeline followed by each pipeline chained to it, skipping those whose `&&` or
+    /// `||` connector is not satisfied by the previous exit status. Returns the status of the last
+    /// pipeline of the chain, or `None` if it was skipped, as then the failure of an earlier one
+    /// was expected.
+    fn run_pipeline(&mut self, pipeline: &Pipeline, commands: &HashMap<&str, Command>) -> Option<i32> {
+        let mut exit_status = self.run_single_pipeline(pipeline, commands);
+        for &(connector, ref next) in &pipeline.chain {
+            exit_status = if connector.should_run(self.history.previous_status) {
+                self.run_single_pipeline(next, commands)
+            } else {
+                None
+            };
         }
         exit_status
     }
 
-    /// Evaluates the given file and returns 'SUCCESS' if it succeeds.
+    fn run_single_pipeline(&mut self, pipeline: &Pipeline, commands: &HashMap<&str, Command>) -> Option<i32> {
+        let pipeline = self.aliases.expand(pipeline);
+        let pipeline = match self.expand_pipeline(&pipeline, commands) {
+            Ok(pipeline) => pipeline,
+            Err(message) => {
+                error(&pipeline.jobs[0].command, message);
+                self.variables.set_var("?", &FAILURE.to_string());
+                self.history.previous_status = FAILURE;
+                return Some(FAILURE);
+            }
+        };
+        if self.options.xtrace {
+            let jobs: Vec<String> = pipeline.jobs.iter().map(|job| {
+                job.args.iter().map(|arg| escape(arg)).collect::<Vec<String>>().join(" ")
+            }).collect();
+            let background = if pipeline.is_background() { " &" } else { "" };
+            let _ = writeln!(io::stderr(), "+ {}{}", jobs.join(" | "), background);
+        }
+        let internal: Vec<bool> = pipeline.jobs.iter().map(|job| self.is_internal(&job.command, commands)).collect();
+        let exit_status = if internal.len() == 1 && internal[0] && !pipeline.is_background() {
+            // A lone builtin or function runs in the shell itself, so that it can change its state
+            let job = &pipeline.jobs[0];
+            match with_redirections(&pipeline.redirections, || self.run_internal(job, commands)) {
+                Ok(code) => code,
+                Err(message) => {
+                    error(&job.command, message);
+                    FAILURE
+                }
+            }
+        } else {
+            let terminal = self.job_control.terminal();
+            let run = |job: &Job| {
+                // This runs in a fork of the shell, whose own pipelines stay in its process group
+                self.job_control.disable();
+                self.traps.clear();
+                self.run_internal(job, commands)
+            };
+            match spawn_pipeline(&pipeline, &internal, terminal, run) {
+                Ok(pids) => finish_pipeline(&pipeline, pids, &mut self.job_control),
+                Err(message) => {
+                    error(&pipeline.jobs[0].command, message);
+                    FAILURE
+                }
+            }
+        };
+        self.variables.set_var("?", &exit_status.to_string());
+        self.history.previous_status = exit_status;
+        Some(exit_status)
+    }
+
+    /// Whether the command is a builtin or a function, which are run by the shell itself.
+    fn is_internal(&self, command: &str, commands: &HashMap<&str, Command>) -> bool {
+        commands.contains_key(command) || self.functions.contains_key(command)
+    }
+
+    /// Runs a job whose command is a builtin or a function and returns its exit status.
+    fn run_internal(&mut self, job: &Job, commands: &HashMap<&str, Command>) -> i32 {
+        if let Some(command) = commands.get(job.command.as_str()) {
+            return (*command.main)(job.args.as_slice(), self);
+        }
+        let function = match self.functions.get(job.command.as_str()).cloned() {
+            Some(function) => function,
+            None => return NO_SUCH_COMMAND,
+        };
+        if job.args.len() - 1 == function.args.len() {
+            let mut variables_backup: HashMap<&str, Option<String>> = HashMap::new();
+            for (name, value) in function.args.iter().zip(job.args.iter().skip(1)) {
+                variables_backup.insert(name, self.variables.get_var(name));
+                self.variables.set_var(name, value);
+            }
+            self.execute_pipelines(function.pipelines.clone(), commands);
+            for (name, value_option) in &variables_backup {
+                match *value_option {
+                    Some(ref value) => self.variables.set_var(name, value),
+                    None => {self.variables.unset_var(name);},
+                }
+            }
+            self.history.previous_status
+        } else {
+            error(&function.name, format!("takes {} arguments, but {} were given", function.args.len(),
+                                          job.args.len() - 1));
+            NO_SUCH_COMMAND // not sure if this is the right error code
+        }
+    }
+
+    fn expand_pipeline(&mut self, pipeline: &Pipeline, commands: &HashMap<&str, Command>) -> Result<Pipeline, String> {
+        let mut jobs = vec![];
+        for job in &pipeline.jobs {
+            // The brackets of an array literal are not a glob, so `let x = [a]` is not replaced
+            // with the file `a`
+            let array_literal = job.args[0] == "let" && Variables::is_array_assignment(&job.args);
+            let mut args = vec![];
+            for arg in &job.args {
+                if arg == "$@" || arg == "\"$@\"" {
+                    // Each positional argument stays a separate argument
+                    args.extend(self.variables.args().iter().cloned());
+                } else if let Some(elements) = self.variables.splat(arg) {
+                    args.extend(elements);
+                } else if array_literal {
+                    args.push(try!(self.expand_word(arg, commands)));
+                } else {
+                    args.extend(try!(self.expand_argument(arg, commands)));
+                }
+            }
+            if args.is_empty() {
+                args.push(String::new());
+            }
+            jobs.push(Job::new(args, job.background));
+        }
+        let mut redirections = vec![];
+        for redirection in &pipeline.redirections {
+            redirections.push(match redirection.target {
+                RedirectionTarget::File(ref file) => {
+                    Redirection::file(redirection.fd, redirection.mode, &try!(self.expand_word(file, commands)))
+                },
+                RedirectionTarget::HereString(ref word) => {
+                    Redirection::here_string(redirection.fd, &try!(self.expand_word(word, commands)))
+                },
+                RedirectionTarget::HereDocument { ref delimiter, ref body, expand: true } => {
+                    Redirection {
+                        target: RedirectionTarget::HereDocument {
+                            delimiter: delimiter.clone(),
+                            body: try!(self.expand_here_document(body, commands)),
+                            expand: false,
+                        },
+                        ..redirection.clone()
+                    }
+                },
+                _ => redirection.clone(),
+            });
+        }
+        Ok(Pipeline::new(jobs, redirections))
+    }
+
+    /// Expands a word to the single argument it stands for, without matching globs.
+    fn expand_word(&mut self, word: &str, commands: &HashMap<&str, Command>) -> Result<String, String> {
+        self.expand_quoted_word(word, commands).map(|(text, _)| text)
+    }
+
+    /// Expands a word to the arguments it stands for: the files matching it if an unquoted part
+    /// of it is a glob that matches any, and the word itself otherwise.
+    fn expand_argument(&mut self, word: &str, commands: &HashMap<&str, Command>) -> Result<Vec<String>, String> {
+        let (text, pattern) = try!(self.expand_quoted_word(word, commands));
+        if let Some(pattern) = pattern {
+            if let Ok(paths) = glob(&pattern) {
+                let matches: Vec<String> = paths.filter_map(Result::ok)
+                                                .map(|path| path.to_string_lossy().into_owned())
+                                                .collect();
+                if !matches.is_empty() {
+                    return Ok(matches);
+                }
+            }
+        }
+        Ok(vec![text])
+    }
+
+    /// Removes the quotes of a word and expands its unquoted and double quoted parts. Returns the
+    /// expanded text, and the glob pattern to match files against if an unquoted part has a glob
+    /// character, in which the quoted parts are escaped.
+    fn expand_quoted_word(&mut self, word: &str, commands: &HashMap<&str, Command>) -> Result<(String, Option<String>), String> {
+        let mut text = String::new();
+        let mut pattern = String::new();
+        let mut has_glob = false;
+        for (index, part) in split_quotes(word).into_iter().enumerate() {
+            match part.quoting {
+                Quoting::Single => {
+                    pattern.push_str(&Pattern::escape(&part.text));
+                    text.push_str(&part.text);
+                },
+                Quoting::Double => {
+                    let expanded = try!(self.expand_substitutions(&part.text, commands));
+                    pattern.push_str(&Pattern::escape(&expanded));
+                    text.push_str(&expanded);
+                },
+                Quoting::Unquoted => {
+                    // Only a `~` at the start of the word is the home directory
+                    let part_text = if index == 0 { self.variables.tilde_expansion(part.text) } else { part.text };
+                    let expanded = try!(self.expand_substitutions(&part_text, commands));
+                    has_glob = has_glob || is_glob(&expanded);
+                    pattern.push_str(&expanded);
+                    text.push_str(&expanded);
+                },
+            }
+        }
+        Ok((text, if has_glob { Some(pattern) } else { None }))
+    }
+
+    /// Expands the body of a here-document, whose quotes are taken literally.
+    fn expand_here_document(&mut self, body: &str, commands: &HashMap<&str, Command>) -> Result<String, String> {
+        let mut expanded = String::new();
+        for part in split_here_document(body) {
+            match part.quoting {
+                Quoting::Single => expanded.push_str(&part.text),
+                _ => expanded.push_str(&try!(self.expand_substitutions(&part.text, commands))),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Expands the variables of the text and replaces each `$(command)` in it with the output of
+    /// the command and each `$((expression))` with its value. Fails if a `${...}` expansion or an
+    /// expression does.
+    fn expand_substitutions(&mut self, text: &str, commands: &HashMap<&str, Command>) -> Result<String, String> {
+        let mut expanded = String::new();
+        for segment in split_substitutions(text) {
+            match segment {
+                Segment::Text(text) => expanded.push_str(&try!(self.variables.expand_string_checked(text))),
+                Segment::Command(command) => expanded.push_str(&self.command_output(command, commands)),
+                Segment::Arithmetic(expression) => {
+                    let expression = try!(self.expand_word(expression, commands));
+                    let value = try!(evaluate(&expression, |name| self.variables.get_var(name))
+                                         .map_err(|message| format!("{}: {}", expression.trim(), message)));
+                    expanded.push_str(&value.to_string());
+                },
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Runs the command and returns what it wrote to stdout, without the trailing newlines.
+    fn command_output(&mut self, command: &str, commands: &HashMap<&str, Command>) -> String {
+        let pipelines = match parse(command) {
+            Ok(pipelines) => pipelines,
+            Err(err) => {
+                let _ = writeln!(io::stderr(), "{}", err.report("command substitution"));
+                return String::new();
+            }
+        };
+        match self.ignoring_errors(|shell| capture_stdout(|| shell.execute_pipelines(pipelines, commands))) {
+            Ok(output) => output.trim_right_matches('\n').to_string(),
+            Err(err) => {
+                error("command substitution", format!("failed to capture the output of {}: {}", command, err));
+                String::new()
+            }
+        }
+    }
+
+    /// Calls a completion function with the command line before the word being completed and the
+    /// word, and returns what it wrote to stdout. The exit status of the last command is kept.
+    fn completion_function_output(&mut self, function: &str, command_line: &str, word: &str) -> String {
+        let commands = Command::map();
+        let args = vec![function.to_string(), escape(command_line), escape(word)];
+        let pipeline = Pipeline::new(vec![Job::new(args, false)], vec![]);
+        let previous_status = self.history.previous_status;
+        let output = self.ignoring_errors(|shell| capture_stdout(|| { shell.run_pipeline(&pipeline, &commands); }));
+        self.history.previous_status = previous_status;
+        output.unwrap_or(String::new())
+    }
+
+    /// Runs the closure without ending the shell on a failing pipeline with `set -e` or running the
+    /// `ERR` trap, as for conditions, whose failures are expected, and for code whose output is
+    /// captured, which the shell would otherwise exit from with its stdout still redirected.
+    fn ignoring_errors<T, F: FnOnce(&mut Shell) -> T>(&mut self, run: F) -> T {
+        let errexit = mem::replace(&mut self.options.errexit, false);
+        let ignore_errors = mem::replace(&mut self.traps.ignore_errors, true);
+        let result = run(self);
+        self.options.errexit = errexit;
+        self.traps.ignore_errors = ignore_errors;
+        result
+    }
+
+    /// Evaluates the given file and returns 'SUCCESS' if it succeeds. Arguments after the file
+    /// replace the positional arguments while it is evaluated.
     fn source_command(&mut self, arguments: &[String]) -> i32 {
         let commands = Command::map();
         match arguments.iter().skip(1).next() {
             Some(argument) => {
-                if let Ok(mut file) = File::open(&argument) {
-                    let mut command_list = String::new();
-                    if let Err(message) = file.read_to_string(&mut command_list) {
-                        println!("{}: Failed to read {}", message, argument);
+                match File::open(&argument) {
+                    Ok(mut file) => {
+                        let mut command_list = String::new();
+                        if let Err(message) = file.read_to_string(&mut command_list) {
+                            error("source", format!("{}: {}", argument, message));
+                            status::FAILURE
+                        } else if arguments.len() > 2 {
+                            let mut positional = vec![self.variables.get_var("0").unwrap_or(String::new())];
+                            positional.extend(arguments[2..].iter().cloned());
+                            let previous = self.variables.set_args(positional);
+                            let parsed = self.on_command(&command_list, argument, &commands);
+                            self.variables.set_args(previous);
+                            if parsed { status::SUCCESS } else { SYNTAX_ERROR }
+                        } else if self.on_command(&command_list, argument, &commands) {
+                            status::SUCCESS
+                        } else {
+                            SYNTAX_ERROR
+                        }
+                    },
+                    Err(message) => {
+                        error("source", format!("{}: {}", argument, message));
                         status::FAILURE
-                    } else {
-                        self.on_command(&command_list, &commands);
-                        status::SUCCESS
                     }
-                } else {
-                    println!("Failed to open {}", argument);
-                    status::FAILURE
                 }
             },
             None => {
@@ -323,6 +828,47 @@ impl Shell {
     }
 }
 
+impl EditorContext for Shell {
+    fn history(&self) -> &VecDeque<String> {
+        self.history.commands()
+    }
+
+    fn history_file(&self) -> Option<String> {
+        History::file_path(&self.variables)
+    }
+
+    /// Completes variable names after a `$`, builtins, functions and executables on `$PATH` in
+    /// the position of a command, the arguments of commands with a completion spec from their
+    /// spec, and file paths otherwise.
+    fn complete(&mut self, line: &str) -> (usize, Vec<String>) {
+        let current = current_word(line);
+        let word = current.word;
+        let candidates = if let Some(candidates) = complete_variable(word, self.variables.names()) {
+            candidates
+        } else if let Some(spec) = self.completions.get(current.command).cloned() {
+            let mut candidates = spec.words.clone();
+            if let Some(ref function) = spec.function {
+                let output = self.completion_function_output(function, current.command_line, word);
+                candidates.extend(output.split_whitespace().map(|candidate| candidate.to_string()));
+            }
+            let mut candidates = complete_names(word, candidates);
+            if spec.glob.is_some() {
+                let expanded = self.variables.tilde_expansion(word.to_string());
+                candidates.extend(complete_path(word, &expanded, false).into_iter().filter(|path| spec.allows_path(path)));
+            }
+            candidates
+        } else if current.is_command && !word.contains('/') {
+            let mut names: Vec<String> = Command::map().keys().map(|name| name.to_string()).collect();
+            names.extend(self.functions.keys().cloned());
+            names.extend(executables());
+            complete_names(word, names)
+        } else {
+            complete_path(word, &self.variables.tilde_expansion(word.to_string()), current.is_command)
+        };
+        (current.start, candidates)
+    }
+}
+
 /// Structure which represents a Terminal's command.
 /// This command structure contains a name, and the code which run the
 /// functionnality associated to this one, with zero, one or several argument(s).
@@ -370,12 +916,11 @@ impl Command {
                             name: "exit",
                             help: "To exit the curent session",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                if let Some(status) = args.get(1) {
-                                    if let Ok(status) = status.parse::<i32>() {
-                                        process::exit(status);
-                                    }
-                                }
-                                process::exit(shell.history.previous_status);
+                                let status = match args.get(1).and_then(|status| status.parse::<i32>().ok()) {
+                                    Some(status) => status,
+                                    None => shell.history.previous_status,
+                                };
+                                shell.exit(status, &Command::map());
                             },
                         });
 
@@ -388,6 +933,15 @@ impl Command {
                             },
                         });
 
+        commands.insert("math",
+                        Command {
+                            name: "math",
+                            help: "Evaluate an arithmetic expression and print the result\n    math <expression>",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                math(args, &shell.variables)
+                            },
+                        });
+
         commands.insert("read",
                         Command {
                             name: "read",
@@ -427,16 +981,17 @@ impl Command {
         commands.insert("if",
                         Command {
                             name: "if",
-                            help: "Conditionally execute code",
-                            main: box |args: &[String], shell: &mut Shell| -> i32 {
-                                shell.flow_control.if_(args)
+                            help: "Conditionally execute code\n    if <left> <comparison> <right>\n    if [not] <pipeline>",
+                            main: box |_: &[String], _: &mut Shell| -> i32 {
+                                error("if", "must be at the beginning of a command");
+                                status::FAILURE
                             },
                         });
 
         commands.insert("else",
                         Command {
                             name: "else",
-                            help: "Execute code if a previous condition was false",
+                            help: "Execute code if the previous conditions were false\n    else\n    else if <condition>\n    elif <condition>",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
                                 shell.flow_control.else_(args)
                             },
@@ -460,16 +1015,146 @@ impl Command {
                             },
                         });
 
+        commands.insert("jobs",
+                        Command {
+                            name: "jobs",
+                            help: "Display the background jobs",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.job_control.jobs(args)
+                            },
+                        });
+
+        commands.insert("fg",
+                        Command {
+                            name: "fg",
+                            help: "Continue a job in the foreground\n    fg [%job]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.job_control.fg(args)
+                            },
+                        });
+
+        commands.insert("bg",
+                        Command {
+                            name: "bg",
+                            help: "Continue a stopped job in the background\n    bg [%job]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.job_control.bg(args)
+                            },
+                        });
+
+        commands.insert("wait",
+                        Command {
+                            name: "wait",
+                            help: "Wait for background jobs to finish\n    wait [%job...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.job_control.wait(args)
+                            },
+                        });
+
+        commands.insert("disown",
+                        Command {
+                            name: "disown",
+                            help: "Remove jobs from the job table without killing them\n    disown [-a] [%job...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.job_control.disown(args)
+                            },
+                        });
+
+        commands.insert("complete",
+                        Command {
+                            name: "complete",
+                            help: "Set how the arguments of commands are completed\n    complete [-w <words>] [-f <function>] [-g <glob>] <command>...\n    complete -r <command>...\n    complete [<command>...]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.completions.complete(args)
+                            },
+                        });
+
+        commands.insert("trap",
+                        Command {
+                            name: "trap",
+                            help: "Run code when the shell receives a signal or exits\n    trap <code> <signal>...\n    trap - <signal>...\n    trap [-l]",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.traps.trap(args)
+                            },
+                        });
+
+        commands.insert("alias",
+                        Command {
+                            name: "alias",
+                            help: "Define aliases, which replace the name of a command with other words\n    alias [<name>=<value>...]\n    alias <name>...",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.aliases.alias(args)
+                            },
+                        });
+
+        commands.insert("unalias",
+                        Command {
+                            name: "unalias",
+                            help: "Remove aliases\n    unalias -a\n    unalias <name>...",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.aliases.unalias(args)
+                            },
+                        });
+
+        commands.insert("set",
+                        Command {
+                            name: "set",
+                            help: "Turn options of the shell on with -, or off with +\n    set [-+eux] [-+o errexit|nounset|xtrace|pipefail]...",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                let status = shell.options.set(args);
+                                shell.variables.nounset = shell.options.nounset;
+                                shell.job_control.pipefail = shell.options.pipefail;
+                                status
+                            },
+                        });
+
+        commands.insert("while",
+                        Command {
+                            name: "while",
+                            help: "Execute code as long as a condition is true\n    while <left> <comparison> <right>\n    while [not] <pipeline>",
+                            main: box |_: &[String], _: &mut Shell| -> i32 {
+                                error("while", "must be at the beginning of a command");
+                                status::FAILURE
+                            },
+                        });
+
+        commands.insert("break",
+                        Command {
+                            name: "break",
+                            help: "Exit from the innermost for or while loop",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.flow_control.break_(args)
+                            },
+                        });
+
+        commands.insert("continue",
+                        Command {
+                            name: "continue",
+                            help: "Skip to the next iteration of the innermost for or while loop",
+                            main: box |args: &[String], shell: &mut Shell| -> i32 {
+                                shell.flow_control.continue_(args)
+                            },
+                        });
+
         commands.insert("source",
                         Command {
                             name: "source",
-                            help: "Evaluate the file following the command or re-initialize the init file",
+                            help: "Evaluate the file following the command or re-initialize the init file\n    source [<file> [<argument>...]]",
                             main: box |args: &[String], shell: &mut Shell| -> i32 {
                                 shell.source_command(args)
 
                             },
                         });
 
+        commands.insert("test",
+                        Command {
+                            name: "test",
+                            help: "Compare two values or check a string or file\n    test <left> <comparison> <right>\n    test -z|-n <string>\n    test -e|-f|-d|-s|-x <file>",
+                            main: box |args: &[String], _: &mut Shell| -> i32 {
+                                test(args)
+                            },
+                        });
+
         commands.insert("true",
                         Command {
                             name: "true",
@@ -528,15 +1213,12 @@ impl Command {
                                    commands if none specified\n    help <command>",
                             main: box move |args: &[String], _: &mut Shell| -> i32 {
                                 if let Some(command) = args.get(1) {
-                                    if command_helper.contains_key(command.as_str()) {
-                                        match command_helper.get(command.as_str()) {
-                                            Some(help) => println!("{}", help),
-                                            None => {
-                                                println!("Command helper not found [run 'help']...")
-                                            }
+                                    match command_helper.get(command.as_str()) {
+                                        Some(help) => println!("{}", help),
+                                        None => {
+                                            error("help", format!("no such builtin: {} [run 'help']", command));
+                                            return FAILURE;
                                         }
-                                    } else {
-                                        println!("Command helper not found [run 'help']...");
                                     }
                                 } else {
                                     for command in command_helper.keys() {
@@ -554,3 +1236,222 @@ impl Command {
 fn main() {
     Shell::default().execute();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use pipe::tests::lock_descriptors;
+
+    /// Executes the code in the shell and returns what it wrote to stdout.
+    fn output(shell: &mut Shell, code: &str) -> String {
+        let commands = Command::map();
+        let _lock = lock_descriptors();
+        capture_stdout(|| { shell.execute_code(code, "test", &commands); }).unwrap()
+    }
+
+    #[test]
+    fn errexit_and_err_trap_stop_at_captured_output() {
+        let _lock = lock_descriptors();
+        let commands = Command::map();
+        let mut shell = Shell::new();
+        shell.options.errexit = true;
+        shell.traps.trap(vec!["trap", "echo trapped", "ERR"]);
+        // The shell would exit here if `set -e` applied, and the trap would add to the output
+        assert_eq!("after", shell.command_output("false; echo after", &commands));
+        assert!(shell.execute_code("fn complete_nothing line word\n  false\nend", "test", &commands));
+        assert_eq!("", shell.completion_function_output("complete_nothing", "cmd ", ""));
+        assert!(shell.options.errexit);
+        assert!(!shell.traps.ignore_errors);
+    }
+
+    #[test]
+    fn while_loop_with_break_and_continue() {
+        let mut shell = Shell::new();
+        let code = "let i = 0
+while $i != 10
+  let i = $(( $i + 1 ))
+  if $i == 3
+    continue
+  end
+  if $i == 5
+    break
+  end
+  echo $i
+end";
+        assert_eq!("1\n2\n4\n", output(&mut shell, code));
+        // The ifs left by break and continue are closed
+        assert!(shell.flow_control.modes.is_empty());
+        assert_eq!(0, shell.flow_control.loop_depth);
+    }
+
+    #[test]
+    fn break_and_continue_apply_to_the_innermost_loop() {
+        let mut shell = Shell::new();
+        let code = "for a in 1 2 3
+  for b in x y z
+    if $b == y
+      break
+    end
+    echo $a$b
+  end
+  if $a == 2
+    if true
+      continue
+    end
+  end
+  echo end $a
+end";
+        assert_eq!("1x\nend 1\n2x\n3x\nend 3\n", output(&mut shell, code));
+        assert!(shell.flow_control.modes.is_empty());
+        assert!(shell.flow_control.loop_control.is_none());
+    }
+
+    #[test]
+    fn break_outside_of_a_loop() {
+        let mut shell = Shell::new();
+        assert_eq!("after\n", output(&mut shell, "break\necho after"));
+        assert!(shell.flow_control.loop_control.is_none());
+        assert_eq!("", output(&mut shell, "continue"));
+        assert_eq!(FAILURE, shell.history.previous_status);
+    }
+
+    #[test]
+    fn blocks_nested_in_loops_and_functions() {
+        let mut shell = Shell::new();
+        let code = "for x in a b c
+  if $x == b
+    echo found $x
+  end
+end";
+        assert_eq!("found b\n", output(&mut shell, code));
+        let code = "fn each prefix
+  for x in 1 2
+    echo $prefix$x
+  end
+end
+each a
+each b";
+        assert_eq!("a1\na2\nb1\nb2\n", output(&mut shell, code));
+        let code = "if true
+  fn greet
+    echo hello
+  end
+end
+greet";
+        assert_eq!("hello\n", output(&mut shell, code));
+        assert!(shell.flow_control.modes.is_empty());
+        assert!(shell.flow_control.nested_blocks.is_empty());
+    }
+
+    #[test]
+    fn blocks_nested_in_a_skipped_branch() {
+        let mut shell = Shell::new();
+        let code = "if false
+  for x in a b
+    echo $x
+  end
+  fn never
+    echo never
+  end
+else
+  echo skipped
+end
+echo after";
+        assert_eq!("skipped\nafter\n", output(&mut shell, code));
+        assert!(shell.flow_control.modes.is_empty());
+        assert!(!shell.functions.contains_key("never"));
+    }
+
+    #[test]
+    fn prompt_of_nested_blocks() {
+        let mut shell = Shell::new();
+        assert_eq!("", output(&mut shell, "for x in a b\n  if $x == a"));
+        assert!(shell.prompt().ends_with("for if> "));
+        assert_eq!("", output(&mut shell, "    echo first\n  end"));
+        assert!(shell.prompt().ends_with("for> "));
+        assert_eq!("first\n", output(&mut shell, "end"));
+        assert!(shell.flow_control.nested_blocks.is_empty());
+    }
+
+    #[test]
+    fn else_if_chains() {
+        let mut shell = Shell::new();
+        let code = "fn pick n
+  if $n == 1
+    echo first
+  else if $n == 2
+    echo second
+  elif $n == 3
+    echo third
+  else
+    echo other
+  end
+end
+pick 1
+pick 2
+pick 3
+pick 4";
+        assert_eq!("first\nsecond\nthird\nother\n", output(&mut shell, code));
+    }
+
+    #[test]
+    fn else_if_conditions_after_the_taken_branch_are_not_evaluated() {
+        let mut shell = Shell::new();
+        let code = "fn check name
+  echo checked $name
+  test $name == b
+end";
+        assert_eq!("", output(&mut shell, code));
+        let chain = "if check a
+  echo a taken
+else if check b
+  echo b taken
+else if check c
+  echo c taken
+end";
+        assert_eq!("checked a\nchecked b\nb taken\n", output(&mut shell, chain));
+        let chain = "if true
+  echo first taken
+else if check b
+  echo b taken
+elif check c
+  echo c taken
+end";
+        assert_eq!("first taken\n", output(&mut shell, chain));
+        assert!(shell.flow_control.modes.is_empty());
+    }
+
+    #[test]
+    fn else_if_chain_in_a_skipped_branch() {
+        let mut shell = Shell::new();
+        let code = "fn check name
+  echo checked $name
+end
+if false
+  if check a
+    echo a
+  else if check b
+    echo b
+  else
+    echo c
+  end
+else
+  echo outer else
+end";
+        assert_eq!("outer else\n", output(&mut shell, code));
+        assert!(shell.flow_control.modes.is_empty());
+    }
+
+    #[test]
+    fn array_literal_is_not_a_glob() {
+        // `[q]` would match the file `q` in the current directory
+        File::create("q").unwrap();
+        let mut shell = Shell::new();
+        let listed = output(&mut shell, "let x = [q]\nlet y=[q r]\necho [q]");
+        let _ = fs::remove_file("q");
+        assert_eq!("q\n", listed);
+        assert_eq!(Some(&vec!["q".to_string()]), shell.variables.get_array("x"));
+        assert_eq!(Some(&vec!["q".to_string(), "r".to_string()]), shell.variables.get_array("y"));
+    }
+}
@@ -0,0 +1,117 @@
+use super::diagnostics::error;
+use super::status::{SUCCESS, FAILURE};
+
+/// The options of the shell that `set` turns on and off.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Options {
+    /// `set -e`: a failing pipeline that is not a condition ends the shell
+    pub errexit: bool,
+    /// `set -u`: expanding an unset variable is an error
+    pub nounset: bool,
+    /// `set -x`: each pipeline is printed to stderr once it is expanded
+    pub xtrace: bool,
+    /// `set -o pipefail`: a pipeline fails with the status of its last failing command
+    pub pipefail: bool,
+}
+
+/// The letter and the name of each option.
+const OPTIONS: [(char, &'static str); 4] = [('e', "errexit"), ('u', "nounset"), ('x', "xtrace"), ('\0', "pipefail")];
+
+impl Options {
+    fn option(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "errexit" => Some(&mut self.errexit),
+            "nounset" => Some(&mut self.nounset),
+            "xtrace" => Some(&mut self.xtrace),
+            "pipefail" => Some(&mut self.pipefail),
+            _ => None,
+        }
+    }
+
+    fn print(&mut self) {
+        for &(_, name) in &OPTIONS {
+            let value = self.option(name).map_or(false, |value| *value);
+            println!("{:<10}{}", name, if value { "on" } else { "off" });
+        }
+    }
+
+    /// Turns options on with `-e` or `-o errexit`, or off with `+e` or `+o errexit`. Several
+    /// letters can be combined, as in `set -eu`. Without arguments, or with only `-o`, the
+    /// options are printed.
+    pub fn set<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let args: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+        if args.is_empty() || args.len() == 1 && args[0] == "-o" {
+            self.print();
+            return SUCCESS;
+        }
+        // The options are only changed if all of the arguments are valid
+        let mut options = *self;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = arg.starts_with('-');
+            if !value && !arg.starts_with('+') || arg.len() < 2 {
+                error("set", format!("invalid option: {}", arg));
+                return FAILURE;
+            }
+            if &arg[1..] == "o" {
+                let name = match args.next() {
+                    Some(name) => name,
+                    None => {
+                        error("set", format!("{}: option requires a name", arg));
+                        return FAILURE;
+                    }
+                };
+                match options.option(name) {
+                    Some(option) => *option = value,
+                    None => {
+                        error("set", format!("invalid option name: {}", name));
+                        return FAILURE;
+                    }
+                }
+                continue;
+            }
+            for letter in arg[1..].chars() {
+                match OPTIONS.iter().find(|&&(option, _)| option == letter) {
+                    Some(&(_, name)) => {
+                        if let Some(option) = options.option(name) {
+                            *option = value;
+                        }
+                    },
+                    None => {
+                        error("set", format!("invalid option: {}{}", &arg[..1], letter));
+                        return FAILURE;
+                    }
+                }
+            }
+        }
+        *self = options;
+        SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use status::{SUCCESS, FAILURE};
+
+    #[test]
+    fn set_and_unset_options() {
+        let mut options = Options::default();
+        assert_eq!(SUCCESS, options.set(vec!["set", "-eu", "-o", "pipefail"]));
+        assert!(options.errexit && options.nounset && options.pipefail && !options.xtrace);
+        assert_eq!(SUCCESS, options.set(vec!["set", "+e", "+o", "nounset", "-x"]));
+        assert!(!options.errexit && !options.nounset && options.pipefail && options.xtrace);
+    }
+
+    #[test]
+    fn invalid_options_change_nothing() {
+        let mut options = Options::default();
+        assert_eq!(FAILURE, options.set(vec!["set", "-e", "-z"]));
+        assert_eq!(FAILURE, options.set(vec!["set", "-e", "-o", "nope"]));
+        assert_eq!(FAILURE, options.set(vec!["set", "-e", "-o"]));
+        assert_eq!(FAILURE, options.set(vec!["set", "e"]));
+        assert_eq!(Options::default(), options);
+    }
+}
@@ -1,37 +1,165 @@
+use std::fmt;
+use std::io::{stderr, Write};
 use std::process::Command;
 
 use self::grammar::pipelines;
-use glob::glob;
+use super::status::SUCCESS;
+use super::here_document;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RedirectionMode {
+    Read,
+    Write,
+    Append,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RedirectionTarget {
+    File(String),
+    /// Another file descriptor, as in `2>&1`
+    Descriptor(i32),
+    /// The word of a here-string, `<<< word`, which is given to the command followed by a newline
+    HereString(String),
+    /// The lines following the command up to the delimiter, whose variables and substitutions are
+    /// expanded unless any of the delimiter is quoted
+    HereDocument {
+        delimiter: String,
+        body: String,
+        expand: bool,
+    },
+}
 
+/// Connects the file descriptor `fd` of a command to a file or to a copy of another descriptor.
 #[derive(Debug, PartialEq, Clone)]
 pub struct Redirection {
-    pub file: String,
-    pub append: bool
+    pub fd: i32,
+    pub mode: RedirectionMode,
+    pub target: RedirectionTarget,
+}
+
+impl Redirection {
+    pub fn file(fd: i32, mode: RedirectionMode, file: &str) -> Self {
+        Redirection {
+            fd: fd,
+            mode: mode,
+            target: RedirectionTarget::File(file.to_string()),
+        }
+    }
+
+    pub fn duplicate(fd: i32, mode: RedirectionMode, source: i32) -> Self {
+        Redirection {
+            fd: fd,
+            mode: mode,
+            target: RedirectionTarget::Descriptor(source),
+        }
+    }
+
+    pub fn here_string(fd: i32, word: &str) -> Self {
+        Redirection {
+            fd: fd,
+            mode: RedirectionMode::Read,
+            target: RedirectionTarget::HereString(word.to_string()),
+        }
+    }
+
+    /// A here-document whose body is filled in once the lines after the command are known.
+    pub fn here_document(fd: i32, delimiter: &str) -> Self {
+        Redirection {
+            fd: fd,
+            mode: RedirectionMode::Read,
+            target: RedirectionTarget::HereDocument {
+                delimiter: delimiter.to_string(),
+                body: String::new(),
+                expand: !delimiter.contains(|character| character == '"' || character == '\'' || character == '\\'),
+            },
+        }
+    }
+
+    /// `&> file` and `&>> file` send both stdout and stderr to the file.
+    pub fn both(mode: RedirectionMode, file: &str) -> Vec<Self> {
+        vec![Redirection::file(1, mode, file), Redirection::duplicate(2, RedirectionMode::Write, 1)]
+    }
+}
+
+impl fmt::Display for Redirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operator = match self.mode {
+            RedirectionMode::Read => "<",
+            RedirectionMode::Write => ">",
+            RedirectionMode::Append => ">>",
+        };
+        let default_fd = if self.mode == RedirectionMode::Read { 0 } else { 1 };
+        if self.fd != default_fd {
+            try!(write!(f, "{}", self.fd));
+        }
+        match self.target {
+            RedirectionTarget::File(ref file) => write!(f, "{} {}", operator, file),
+            RedirectionTarget::Descriptor(source) => write!(f, "{}&{}", operator, source),
+            RedirectionTarget::HereString(ref word) => write!(f, "<<< {}", word),
+            RedirectionTarget::HereDocument { ref delimiter, .. } => write!(f, "<<{}", delimiter),
+        }
+    }
+}
+
+/// Joins a pipeline to the one before it: `&&` runs it only if the previous pipeline succeeded,
+/// `||` runs it only if the previous pipeline failed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+impl Connector {
+    /// Whether the pipeline following this connector should run given the previous exit status.
+    pub fn should_run(&self, previous_status: i32) -> bool {
+        match *self {
+            Connector::And => previous_status == SUCCESS,
+            Connector::Or => previous_status != SUCCESS,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Pipeline {
     pub jobs: Vec<Job>,
-    pub stdout: Option<Redirection>,
-    pub stdin: Option<Redirection>,
+    /// Redirections in the order they were written, which matters for ones like `2>&1`
+    pub redirections: Vec<Redirection>,
+    /// Pipelines chained after this one with `&&` or `||`, in order
+    pub chain: Vec<(Connector, Pipeline)>,
 }
 
 impl Pipeline {
 
-    pub fn new(jobs: Vec<Job>, stdin: Option<Redirection>, stdout: Option<Redirection>) -> Self {
+    pub fn new(jobs: Vec<Job>, redirections: Vec<Redirection>) -> Self {
         Pipeline {
             jobs: jobs,
-            stdin: stdin,
-            stdout: stdout,
+            redirections: redirections,
+            chain: vec![],
         }
     }
 
-    pub fn expand_globs(&mut self) {
-        let jobs = self.jobs.drain(..).map(|mut job| {
-            job.expand_globs();
-            job
-        }).collect();
-        self.jobs = jobs;
+    pub fn with_chain(mut self, chain: Vec<(Connector, Pipeline)>) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// A pipeline runs in the background when its last job ends with `&`.
+    pub fn is_background(&self) -> bool {
+        self.jobs.last().map_or(false, |job| job.background)
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let jobs: Vec<String> = self.jobs.iter().map(|job| job.args.join(" ")).collect();
+        try!(write!(f, "{}", jobs.join(" | ")));
+        for redirection in &self.redirections {
+            try!(write!(f, " {}", redirection));
+        }
+        for &(connector, ref pipeline) in &self.chain {
+            try!(write!(f, " {} {}", if connector == Connector::And { "&&" } else { "||" }, pipeline));
+        }
+        Ok(())
     }
 }
 
@@ -53,25 +181,6 @@ impl Job {
         }
     }
 
-    pub fn expand_globs(&mut self) {
-        let mut new_args: Vec<String> = vec![];
-        for arg in self.args.drain(..) {
-            let mut pushed_glob = false;
-            if arg.contains(|chr| chr == '?' || chr == '*' || chr == '[') {
-                if let Ok(expanded) = glob(&arg) {
-                    for path in expanded.filter_map(Result::ok) {
-                        pushed_glob = true;
-                        new_args.push(path.to_string_lossy().into_owned());
-                    }
-                }
-            }
-            if !pushed_glob {
-                new_args.push(arg);
-            }
-        }
-        self.args = new_args;
-    }
-
     pub fn build_command(&self) -> Command {
         let mut command = Command::new(&self.command);
         for i in 1..self.args.len() {
@@ -83,70 +192,178 @@ impl Job {
     }
 }
 
-pub fn parse(code: &str) -> Vec<Pipeline> {
-    match pipelines(code) {
-		Ok(code_ok) => code_ok,
-		Err(err) => {
-			println!("ion: Syntax {}",err);
-			vec![]
-		}
-	}
+/// Gives each here-document of the pipelines its body, in the order they were written.
+fn fill_here_documents<I: Iterator<Item = String>>(pipeline: &mut Pipeline, bodies: &mut I) {
+    for redirection in &mut pipeline.redirections {
+        if let RedirectionTarget::HereDocument { ref mut body, .. } = redirection.target {
+            *body = bodies.next().unwrap_or(String::new());
+        }
+    }
+    for &mut (_, ref mut next) in &mut pipeline.chain {
+        fill_here_documents(next, bodies);
+    }
+}
+
+/// An error in the syntax of some code, at a position that is counted from 1.
+#[derive(Debug, PartialEq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub column: usize,
+    /// The line of the code the error is on
+    pub source_line: String,
+    /// The character the error is at, if the code does not end there
+    pub found: Option<char>,
+}
+
+impl SyntaxError {
+    /// Describes the error, with `source` being the name of the file the code came from, and
+    /// shows the line it is on with a caret under the column.
+    pub fn report(&self, source: &str) -> String {
+        // Tabs are kept so that the caret lines up with the line above it
+        let indent: String = self.source_line
+                                 .chars()
+                                 .take(self.column - 1)
+                                 .map(|character| if character == '\t' { '\t' } else { ' ' })
+                                 .collect();
+        format!("ion: {}:{}:{}: {}\n    {}\n    {}^", source, self.line, self.column, self,
+                self.source_line, indent)
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.found {
+            Some('\n') | Some('\r') => write!(f, "syntax error: unexpected end of line"),
+            Some(character) => write!(f, "syntax error: unexpected `{}`", character),
+            None => write!(f, "syntax error: unexpected end of input"),
+        }
+    }
+}
+
+pub fn parse(code: &str) -> Result<Vec<Pipeline>, SyntaxError> {
+    let (extracted, bodies, missing) = here_document::extract(code);
+    if let Some(delimiter) = missing {
+        let _ = writeln!(stderr(), "ion: here-document ended by the end of input instead of {}", delimiter);
+    }
+    match pipelines(&extracted) {
+        Ok(mut pipelines) => {
+            let mut bodies = bodies.into_iter();
+            for pipeline in &mut pipelines {
+                fill_here_documents(pipeline, &mut bodies);
+            }
+            Ok(pipelines)
+        },
+        Err(err) => {
+            Err(SyntaxError {
+                line: err.line,
+                column: err.column,
+                source_line: code.split('\n').nth(err.line - 1).unwrap_or("").trim_right_matches('\r').to_string(),
+                found: extracted[err.offset..].chars().next(),
+            })
+        }
+    }
 }
 
 peg! grammar(r#"
 use super::Pipeline;
 use super::Job;
 use super::Redirection;
+use super::RedirectionMode;
+use super::Connector;
 
 
 #[pub]
 pipelines -> Vec<Pipeline>
-    = (unused* newline)* pipelines:pipeline ++ ((job_ending+ unused*)+) (newline unused*)* { pipelines }
+    = (unused* newline)* pipelines:chained_pipeline ++ ((job_ending+ unused*)+) (newline unused*)* { pipelines }
     / (unused*) ** newline { vec![] }
 
+chained_pipeline -> Pipeline
+    = first:pipeline chain:chain_link* { first.with_chain(chain) }
+
+chain_link -> (Connector, Pipeline)
+    = connector:connector (newline unused*)* pipeline:pipeline { (connector, pipeline) }
+
+connector -> Connector
+    = [&]{2} { Connector::And }
+    / [|]{2} { Connector::Or }
+
 pipeline -> Pipeline
-    = whitespace? res:job ++ pipeline_sep whitespace? redir:redirection whitespace? comment? { Pipeline::new(res, redir.0, redir.1) }
+    = whitespace? res:job ++ pipeline_sep whitespace? redirections:redirection* background:background_token? whitespace? comment? {
+        let mut jobs = res;
+        if background.is_some() {
+            if let Some(job) = jobs.last_mut() {
+                job.background = true;
+            }
+        }
+        Pipeline::new(jobs, redirections.into_iter().flat_map(|redirection| redirection).collect())
+    }
 
 job -> Job
     = args:word ++ whitespace background:background_token? { 
         Job::new(args.iter().map(|arg|arg.to_string()).collect(), background.is_some())
     }
 
-redirection -> (Option<Redirection>, Option<Redirection>)
-    = stdin:redirect_stdin whitespace? stdout:redirect_stdout? { (Some(stdin), stdout) }
-    / stdout:redirect_stdout whitespace? stdin:redirect_stdin? { (stdin, Some(stdout)) }
-    / { (None, None) }
+redirection -> Vec<Redirection>
+    = redirection:redirect whitespace? { redirection }
 
-redirect_stdin -> Redirection
-    = [<] whitespace? file:word { Redirection { file: file.to_string(), append: false } }
+redirect -> Vec<Redirection>
+    = [&] [>]{2} whitespace? file:word { Redirection::both(RedirectionMode::Append, file) }
+    / [&] [>] whitespace? file:word { Redirection::both(RedirectionMode::Write, file) }
+    / fd:fd? [>]{2} whitespace? file:word {
+        vec![Redirection::file(fd.unwrap_or(1), RedirectionMode::Append, file)]
+    }
+    / fd:fd? [>] [&] source:fd { vec![Redirection::duplicate(fd.unwrap_or(1), RedirectionMode::Write, source)] }
+    / fd:fd? [>] whitespace? file:word { vec![Redirection::file(fd.unwrap_or(1), RedirectionMode::Write, file)] }
+    / fd:fd? [<]{3} whitespace? word:word { vec![Redirection::here_string(fd.unwrap_or(0), word)] }
+    / fd:fd? [<]{2} [-]? whitespace? delimiter:word { vec![Redirection::here_document(fd.unwrap_or(0), delimiter)] }
+    / fd:fd? [<] [&] source:fd { vec![Redirection::duplicate(fd.unwrap_or(0), RedirectionMode::Read, source)] }
+    / fd:fd? [<] whitespace? file:word { vec![Redirection::file(fd.unwrap_or(0), RedirectionMode::Read, file)] }
 
-redirect_stdout -> Redirection
-    = [>]{2} whitespace? file:word { Redirection { file: file.to_string(), append: true } }
-    / [>] whitespace? file:word { Redirection { file: file.to_string(), append: false } }
+fd -> i32
+    = [0-9]+ { match_str.parse().unwrap_or(-1) }
 
 pipeline_sep -> ()
     = (whitespace? [|] whitespace?) { }
 
 background_token -> ()
-    = [&]
-    / whitespace [&]
+    = [&] ![&>]
+    / whitespace [&] ![&>]
 
 word -> &'input str
-    = double_quoted_word
-    / single_quoted_word
-    / [^ \t\r\n#;&|<>]+ { match_str }
+    = !([0-9]+ [<>]) !line_continuation word_part+ { match_str }
 
-double_quoted_word -> &'input str
-    = ["] word:_double_quoted_word ["] { word }
+word_part -> ()
+    = double_quoted
+    / single_quoted
+    / [\\] .
+    / command_substitution
+    / parameter_expansion
+    / [$] [#]
+    / [^ \t\r\n#;&|<>"'\\]
 
-_double_quoted_word -> &'input str
-    = [^"]+ { match_str }
+command_substitution -> ()
+    = [$] [(] substitution_body* [)]
 
-single_quoted_word -> &'input str
-    = ['] word:_single_quoted_word ['] { word }
+substitution_body -> ()
+    = command_substitution
+    / [(] substitution_body* [)]
+    / ["] ([\\] . / [^"\\])* ["]
+    / ['] [^']* [']
+    / [\\] .
+    / [^()"'\\]
 
-_single_quoted_word -> &'input str
-    = [^']+ { match_str }
+parameter_expansion -> ()
+    = [$] [{] parameter_body* [}]
+
+parameter_body -> ()
+    = parameter_expansion
+    / [^{}]
+
+double_quoted -> ()
+    = ["] (command_substitution / [\\] . / [^"\\])* ["]
+
+single_quoted -> ()
+    = ['] [^']* [']
 
 unused -> ()
     = whitespace comment? { () }
@@ -156,7 +373,10 @@ comment -> ()
     = [#] [^\r\n]*
 
 whitespace -> ()
-    = [ \t]+
+    = ([ \t] / line_continuation)+
+
+line_continuation -> ()
+    = [\\] [\r]? [\n]
 
 job_ending -> ()
     = [;]
@@ -175,7 +395,7 @@ mod tests {
 
     #[test]
     fn single_job_no_args() {
-        let jobs = parse("cat").remove(0).jobs;
+        let jobs = parse("cat").unwrap().remove(0).jobs;
         assert_eq!(1, jobs.len());
         assert_eq!("cat", jobs[0].command);
         assert_eq!(1, jobs[0].args.len());
@@ -183,7 +403,7 @@ mod tests {
 
     #[test]
     fn single_job_with_args() {
-        let jobs = parse("ls -al dir").remove(0).jobs;
+        let jobs = parse("ls -al dir").unwrap().remove(0).jobs;
         assert_eq!(1, jobs.len());
         assert_eq!("ls", jobs[0].command);
         assert_eq!("-al", jobs[0].args[1]);
@@ -192,7 +412,7 @@ mod tests {
 
     #[test]
     fn multiple_jobs_with_args() {
-        let pipelines = parse("ls -al;cat tmp.txt");
+        let pipelines = parse("ls -al;cat tmp.txt").unwrap();
         assert_eq!(2, pipelines.len());
         assert_eq!("ls", pipelines[0].jobs[0].command);
         assert_eq!("-al", pipelines[0].jobs[0].args[1]);
@@ -202,13 +422,13 @@ mod tests {
 
     #[test]
     fn parse_empty_string() {
-        let pipelines = parse("");
+        let pipelines = parse("").unwrap();
         assert_eq!(0, pipelines.len());
     }
 
     #[test]
     fn multiple_white_space_between_words() {
-        let jobs = parse("ls \t -al\t\tdir").remove(0).jobs;
+        let jobs = parse("ls \t -al\t\tdir").unwrap().remove(0).jobs;
         assert_eq!(1, jobs.len());
         assert_eq!("ls", jobs[0].command);
         assert_eq!("-al", jobs[0].args[1]);
@@ -217,7 +437,7 @@ mod tests {
 
     #[test]
     fn trailing_whitespace() {
-        let pipelines = parse("ls -al\t ");
+        let pipelines = parse("ls -al\t ").unwrap();
         assert_eq!(1, pipelines.len());
         assert_eq!("ls", pipelines[0].jobs[0].command);
         assert_eq!("-al", pipelines[0].jobs[0].args[1]);
@@ -225,70 +445,144 @@ mod tests {
 
     #[test]
     fn double_quoting() {
-        let jobs = parse("echo \"Hello World\"").remove(0).jobs;
+        let jobs = parse("echo \"Hello World\"").unwrap().remove(0).jobs;
         assert_eq!(2, jobs[0].args.len());
-        assert_eq!("Hello World", jobs[0].args[1]);
+        assert_eq!("\"Hello World\"", jobs[0].args[1]);
     }
 
     #[test]
     fn all_whitespace() {
-        let pipelines = parse("  \t ");
+        let pipelines = parse("  \t ").unwrap();
         assert_eq!(0, pipelines.len());
     }
 
     #[test]
     fn not_background_job() {
-        let jobs = parse("echo hello world").remove(0).jobs;
+        let jobs = parse("echo hello world").unwrap().remove(0).jobs;
         assert_eq!(false, jobs[0].background);
     }
 
     #[test]
     fn background_job() {
-        let jobs = parse("echo hello world&").remove(0).jobs;
+        let jobs = parse("echo hello world&").unwrap().remove(0).jobs;
         assert_eq!(true, jobs[0].background);
     }
 
     #[test]
     fn background_job_with_space() {
-        let jobs = parse("echo hello world &").remove(0).jobs;
+        let jobs = parse("echo hello world &").unwrap().remove(0).jobs;
         assert_eq!(true, jobs[0].background);
     }
 
+    #[test]
+    fn and_chain() {
+        let pipelines = parse("make && make install").unwrap();
+        assert_eq!(1, pipelines.len());
+        assert_eq!("make", pipelines[0].jobs[0].command);
+        assert_eq!(false, pipelines[0].jobs[0].background);
+        assert_eq!(1, pipelines[0].chain.len());
+        assert_eq!(Connector::And, pipelines[0].chain[0].0);
+        assert_eq!("install", pipelines[0].chain[0].1.jobs[0].args[1]);
+    }
+
+    #[test]
+    fn mixed_and_or_chain() {
+        let pipelines = parse("test -f x || touch x && echo done; ls").unwrap();
+        assert_eq!(2, pipelines.len());
+        let chain = &pipelines[0].chain;
+        assert_eq!(2, chain.len());
+        assert_eq!(Connector::Or, chain[0].0);
+        assert_eq!("touch", chain[0].1.jobs[0].command);
+        assert_eq!(Connector::And, chain[1].0);
+        assert_eq!("echo", chain[1].1.jobs[0].command);
+        assert_eq!("ls", pipelines[1].jobs[0].command);
+    }
+
+    #[test]
+    fn chain_with_pipes_and_redirection() {
+        let pipelines = parse("cat foo | grep bar > out || echo failed").unwrap();
+        assert_eq!(2, pipelines[0].jobs.len());
+        assert_eq!(vec![Redirection::file(1, RedirectionMode::Write, "out")], pipelines[0].redirections);
+        assert_eq!(Connector::Or, pipelines[0].chain[0].0);
+        assert_eq!("echo", pipelines[0].chain[0].1.jobs[0].command);
+    }
+
+    #[test]
+    fn background_pipeline() {
+        let pipelines = parse("cat foo | grep bar &").unwrap();
+        assert!(pipelines[0].is_background());
+        assert_eq!("cat foo | grep bar", pipelines[0].to_string());
+    }
+
+    #[test]
+    fn command_substitution() {
+        let jobs = parse("let branch=$(git rev-parse --abbrev-ref HEAD)").unwrap().remove(0).jobs;
+        assert_eq!(vec!["let", "branch=$(git rev-parse --abbrev-ref HEAD)"], jobs[0].args);
+    }
+
+    #[test]
+    fn nested_command_substitution_with_pipes() {
+        let jobs = parse("echo $(echo $(cat \")\" | wc -l) | tr 1 2) \"$(ls; pwd)\" done").unwrap().remove(0).jobs;
+        assert_eq!(vec!["echo", "$(echo $(cat \")\" | wc -l) | tr 1 2)", "\"$(ls; pwd)\"", "done"], jobs[0].args);
+    }
+
+    #[test]
+    fn argument_count_is_not_a_comment() {
+        let jobs = parse("echo $# args # comment").unwrap().remove(0).jobs;
+        assert_eq!(vec!["echo", "$#", "args"], jobs[0].args);
+    }
+
+    #[test]
+    fn braced_parameters_are_single_words() {
+        let jobs = parse("echo ${NAME:-default value} ${#NAME} ${A:-${B}}").unwrap().remove(0).jobs;
+        assert_eq!(vec!["echo", "${NAME:-default value}", "${#NAME}", "${A:-${B}}"], jobs[0].args);
+    }
+
+    #[test]
+    fn syntax_error_position() {
+        let error = parse("echo a\n\tcat foo | | grep b\necho c").unwrap_err();
+        assert_eq!(SyntaxError { line: 2, column: 12, source_line: "\tcat foo | | grep b".into(), found: Some('|') },
+                   error);
+        assert_eq!("ion: script.ion:2:12: syntax error: unexpected `|`\n    \tcat foo | | grep b\n    \t          ^",
+                   error.report("script.ion"));
+        assert_eq!(None, parse("echo \"unclosed").unwrap_err().found);
+    }
+
     #[test]
     fn lone_comment() {
-        let pipelines = parse("# ; \t as!!+dfa");
+        let pipelines = parse("# ; \t as!!+dfa").unwrap();
         assert_eq!(0, pipelines.len());
     }
 
     #[test]
     fn command_followed_by_comment() {
-        let pipelines = parse("cat # ; \t as!!+dfa");
+        let pipelines = parse("cat # ; \t as!!+dfa").unwrap();
         assert_eq!(1, pipelines.len());
         assert_eq!(1, pipelines[0].jobs[0].args.len());
     }
 
     #[test]
     fn comments_in_multiline_script() {
-        let pipelines = parse("echo\n# a comment;\necho#asfasdf");
+        let pipelines = parse("echo\n# a comment;\necho#asfasdf").unwrap();
         assert_eq!(2, pipelines.len());
     }
 
     #[test]
     fn multiple_newlines() {
-        let pipelines = parse("echo\n\ncat");
+        let pipelines = parse("echo\n\ncat").unwrap();
         assert_eq!(2, pipelines.len());
     }
 
     #[test]
     fn leading_whitespace() {
-        let jobs = parse("    \techo").remove(0).jobs;
+        let jobs = parse("    \techo").unwrap().remove(0).jobs;
         assert_eq!(1, jobs.len());
         assert_eq!("echo", jobs[0].command);
     }
 
     #[test]
     fn indentation_on_multiple_lines() {
-        let pipelines = parse("echo\n  cat");
+        let pipelines = parse("echo\n  cat").unwrap();
         assert_eq!(2, pipelines.len());
         assert_eq!("echo", pipelines[0].jobs[0].command);
         assert_eq!("cat", pipelines[1].jobs[0].command);
@@ -296,49 +590,125 @@ mod tests {
 
     #[test]
     fn single_quoting() {
-        let jobs = parse("echo '#!!;\"\\'").remove(0).jobs;
-        assert_eq!("#!!;\"\\", jobs[0].args[1]);
+        let jobs = parse("echo '#!!;\"\\'").unwrap().remove(0).jobs;
+        assert_eq!("'#!!;\"\\'", jobs[0].args[1]);
     }
 
     #[test]
     fn mixed_quoted_and_unquoted() {
-        let jobs = parse("echo '#!!;\"\\' and \t some \"more' 'stuff\"").remove(0).jobs;
-        assert_eq!("#!!;\"\\", jobs[0].args[1]);
+        let jobs = parse("echo '#!!;\"\\' and \t some \"more' 'stuff\"").unwrap().remove(0).jobs;
+        assert_eq!("'#!!;\"\\'", jobs[0].args[1]);
         assert_eq!("and", jobs[0].args[2]);
         assert_eq!("some", jobs[0].args[3]);
-        assert_eq!("more' 'stuff", jobs[0].args[4]);
+        assert_eq!("\"more' 'stuff\"", jobs[0].args[4]);
+    }
+
+    #[test]
+    fn adjacent_quotes_and_escapes_in_one_word() {
+        let jobs = parse("echo foo\"bar baz\"'; x' a\\ b \\; \"\\\"\" ''").unwrap().remove(0).jobs;
+        assert_eq!(vec!["echo", "foo\"bar baz\"'; x'", "a\\ b", "\\;", "\"\\\"\"", "''"], jobs[0].args);
+    }
+
+    #[test]
+    fn line_continuation() {
+        let pipelines = parse("echo a \\\n  b\\\nc").unwrap();
+        assert_eq!(1, pipelines.len());
+        assert_eq!(vec!["echo", "a", "b\\\nc"], pipelines[0].jobs[0].args);
     }
 
     #[test]
     fn several_blank_lines() {
-        let pipelines = parse("\n\n\n");
+        let pipelines = parse("\n\n\n").unwrap();
         assert_eq!(0, pipelines.len());
     }
 
     #[test]
     fn pipelines_with_redirection() {
-        let pipelines = parse("cat | echo hello | cat < stuff > other");
+        let pipelines = parse("cat | echo hello | cat < stuff > other").unwrap();
         assert_eq!(3, pipelines[0].jobs.len());
-        assert_eq!("stuff", &pipelines[0].clone().stdin.unwrap().file);
-        assert_eq!("other", &pipelines[0].clone().stdout.unwrap().file);
-        assert!(!pipelines[0].clone().stdout.unwrap().append);
+        assert_eq!(Redirection::file(0, RedirectionMode::Read, "stuff"), pipelines[0].redirections[0]);
+        assert_eq!(Redirection::file(1, RedirectionMode::Write, "other"), pipelines[0].redirections[1]);
     }
 
     #[test]
     fn pipeline_with_redirection_append() {
-        let pipelines = parse("cat | echo hello | cat < stuff >> other");
+        let pipelines = parse("cat | echo hello | cat < stuff >> other").unwrap();
         assert_eq!(3, pipelines[0].jobs.len());
-        assert_eq!("stuff", &pipelines[0].clone().stdin.unwrap().file);
-        assert_eq!("other", &pipelines[0].clone().stdout.unwrap().file);
-        assert!(pipelines[0].clone().stdout.unwrap().append);
+        assert_eq!(Redirection::file(0, RedirectionMode::Read, "stuff"), pipelines[0].redirections[0]);
+        assert_eq!(Redirection::file(1, RedirectionMode::Append, "other"), pipelines[0].redirections[1]);
     }
 
     #[test]
     fn pipelines_with_redirection_reverse_order() {
-        let pipelines = parse("cat | echo hello | cat > stuff < other");
+        let pipelines = parse("cat | echo hello | cat > stuff < other").unwrap();
         assert_eq!(3, pipelines[0].jobs.len());
-        assert_eq!("other", &pipelines[0].clone().stdin.unwrap().file);
-        assert_eq!("stuff", &pipelines[0].clone().stdout.unwrap().file);
+        assert_eq!(Redirection::file(1, RedirectionMode::Write, "stuff"), pipelines[0].redirections[0]);
+        assert_eq!(Redirection::file(0, RedirectionMode::Read, "other"), pipelines[0].redirections[1]);
+    }
+
+    #[test]
+    fn stderr_redirection() {
+        let pipelines = parse("make 2> errors 2>> log").unwrap();
+        assert_eq!(1, pipelines[0].jobs[0].args.len());
+        assert_eq!(Redirection::file(2, RedirectionMode::Write, "errors"), pipelines[0].redirections[0]);
+        assert_eq!(Redirection::file(2, RedirectionMode::Append, "log"), pipelines[0].redirections[1]);
+    }
+
+    #[test]
+    fn descriptor_duplication() {
+        let pipelines = parse("make > log 2>&1; echo error >&2; cat 3<input 4>&3").unwrap();
+        assert_eq!(vec![Redirection::file(1, RedirectionMode::Write, "log"),
+                        Redirection::duplicate(2, RedirectionMode::Write, 1)],
+                   pipelines[0].redirections);
+        assert_eq!(vec![Redirection::duplicate(1, RedirectionMode::Write, 2)], pipelines[1].redirections);
+        assert_eq!(vec![Redirection::file(3, RedirectionMode::Read, "input"),
+                        Redirection::duplicate(4, RedirectionMode::Write, 3)],
+                   pipelines[2].redirections);
+    }
+
+    #[test]
+    fn here_documents_and_strings() {
+        let pipelines = parse("cat | sort <<EOF; cat <<-'END' && cat 3<<X\n$x\nEOF\n\tz\n\tEND\ny\nX\ncat <<< \"$a b\"").unwrap();
+        assert_eq!(3, pipelines.len());
+        assert_eq!(vec![Redirection {
+                            fd: 0,
+                            mode: RedirectionMode::Read,
+                            target: RedirectionTarget::HereDocument { delimiter: "EOF".into(), body: "$x\n".into(), expand: true },
+                        }],
+                   pipelines[0].redirections);
+        assert_eq!(RedirectionTarget::HereDocument { delimiter: "'END'".into(), body: "z\n".into(), expand: false },
+                   pipelines[1].redirections[0].target);
+        assert_eq!(3, pipelines[1].chain[0].1.redirections[0].fd);
+        assert_eq!(RedirectionTarget::HereDocument { delimiter: "X".into(), body: "y\n".into(), expand: true },
+                   pipelines[1].chain[0].1.redirections[0].target);
+        assert_eq!(vec![Redirection::here_string(0, "\"$a b\"")], pipelines[2].redirections);
+    }
+
+    #[test]
+    fn redirect_stdout_and_stderr() {
+        let pipelines = parse("make &> log").unwrap();
+        assert_eq!(false, pipelines[0].jobs[0].background);
+        assert_eq!(Redirection::both(RedirectionMode::Write, "log"), pipelines[0].redirections);
+        assert_eq!("make > log 2>&1", pipelines[0].to_string());
+    }
+
+    #[test]
+    fn background_pipeline_with_redirections() {
+        let pipelines = parse("make > out 2>&1 &\nlong_task > log &; echo next").unwrap();
+        assert!(pipelines[0].is_background());
+        assert_eq!(vec![Redirection::file(1, RedirectionMode::Write, "out"),
+                        Redirection::duplicate(2, RedirectionMode::Write, 1)], pipelines[0].redirections);
+        assert!(pipelines[1].is_background());
+        assert_eq!(vec![Redirection::file(1, RedirectionMode::Write, "log")], pipelines[1].redirections);
+        assert_eq!(vec!["echo", "next"], pipelines[2].jobs[0].args);
+        assert!(!pipelines[2].is_background());
+    }
+
+    #[test]
+    fn number_arguments_are_not_redirections() {
+        let pipelines = parse("head -n 2 file2>out").unwrap();
+        assert_eq!(vec!["head", "-n", "2", "file2"], pipelines[0].jobs[0].args);
+        assert_eq!(vec![Redirection::file(1, RedirectionMode::Write, "out")], pipelines[0].redirections);
     }
 
     #[test]
@@ -1,92 +1,526 @@
-use std::process::{Stdio, Command, Child};
-use std::os::unix::io::{FromRawFd, AsRawFd, IntoRawFd};
+use std::io::{self, stdout, Read, Seek, SeekFrom, Write};
+use std::thread;
+use std::process::{Stdio, Command};
+use std::os::unix::io::{FromRawFd, AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::fs::{File, OpenOptions};
+use libc::{self, pid_t};
 
-use super::status::{TERMINATED, NO_SUCH_COMMAND};
-use super::peg::Pipeline;
+use super::status::{SUCCESS, FAILURE, NO_SUCH_COMMAND};
+use super::peg::{Pipeline, Job, Redirection, RedirectionMode, RedirectionTarget};
+use super::job_control::JobControl;
+use super::diagnostics::error;
+use super::signals;
 
-pub fn execute_pipeline(pipeline: Pipeline) -> i32 {
-    let mut piped_commands: Vec<Command> = pipeline.jobs
-                                                   .iter()
-                                                   .map(|job| { job.build_command() })
-                                                   .collect();
-    if let (Some(stdin), Some(command)) = (pipeline.stdin, piped_commands.first_mut()) {
-        match File::open(&stdin.file) {
-            Ok(file) => unsafe { command.stdin(Stdio::from_raw_fd(file.into_raw_fd())); },
-            Err(err) => println!("ion: failed to redirect stdin into {}: {}", stdin.file, err)
-        }
+/// The process group that the processes of a pipeline join when job control is enabled, and the
+/// terminal that the group is given if the pipeline runs in the foreground.
+#[derive(Clone, Copy)]
+struct Group {
+    /// 0 until the first process is started, whose pid becomes the id of the group
+    pgid: pid_t,
+    terminal: Option<RawFd>,
+}
+
+/// How many outputs are being captured by `capture_stdout`, inside of one another.
+static CAPTURING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Starts every job of the pipeline, connecting the stdout of each one to the stdin of the next,
+/// and returns their pids, with `None` for the commands that could not be started. The jobs for
+/// which `internal` is true are builtins or functions, which are run by calling `run` in a fork of
+/// the shell. If job control is enabled, which `terminal` is given for, the processes are put in
+/// a process group of their own. A background pipeline started while the output is captured
+/// writes to /dev/null instead, or the capture would last until the pipeline ends.
+pub fn spawn_pipeline<F>(pipeline: &Pipeline, internal: &[bool], terminal: Option<RawFd>, mut run: F)
+    -> Result<Vec<Option<pid_t>>, String>
+    where F: FnMut(&Job) -> i32
+{
+    let background = pipeline.is_background();
+    let mut group = terminal.map(|terminal| Group {
+        pgid: 0,
+        terminal: if background { None } else { Some(terminal) },
+    });
+    // The opened files have to stay open until every job has been started
+    let mut files: Vec<File> = vec![];
+    let (first, mut last) = try!(redirection_tables(&pipeline.redirections, &mut files));
+    if background && CAPTURING.load(Ordering::SeqCst) > 0 {
+        let null = try!(OpenOptions::new().write(true).open("/dev/null")
+                                          .map_err(|err| format!("/dev/null: {}", err)));
+        last.insert(0, (1, null.as_raw_fd()));
+        files.push(null);
     }
-    if let Some(stdout) = pipeline.stdout {
-        if let Some(mut command) = piped_commands.last_mut() {
-            let file = if stdout.append {
-                OpenOptions::new().write(true).append(true).open(&stdout.file)
-            } else {
-                File::create(&stdout.file)
-            };
-            match file {
-                Ok(f) => unsafe { command.stdout(Stdio::from_raw_fd(f.into_raw_fd())); },
-                Err(err) => println!("ion: failed to redirect stdout into {}: {}", stdout.file, err)
+    let end = pipeline.jobs.len() - 1;
+    let mut pids = vec![];
+    // The read end of the pipe from the previous job
+    let mut input: Option<File> = None;
+    for (index, job) in pipeline.jobs.iter().enumerate() {
+        // Pairs of (descriptor of the job, descriptor of the shell it should be a copy of)
+        let mut table: Vec<(RawFd, RawFd)> = vec![];
+        if let Some(ref input) = input {
+            table.push((0, input.as_raw_fd()));
+        }
+        let (next_input, output) = if index < end {
+            let (read_end, write_end) = try!(create_pipe());
+            table.push((1, write_end.as_raw_fd()));
+            (Some(read_end), Some(write_end))
+        } else {
+            (None, None)
+        };
+        if index == 0 {
+            table.extend(first.iter().cloned());
+        }
+        if index == end {
+            table.extend(last.iter().cloned());
+        }
+        let pid = if internal[index] {
+            let unused: Vec<RawFd> = next_input.iter().map(|file| file.as_raw_fd()).collect();
+            fork_stage(job, &table, &unused, group, &mut run)
+        } else {
+            spawn(job, table, group)
+        };
+        if let (Some(pid), Some(group)) = (pid, group.as_mut()) {
+            // The child joins the group itself too, but it must be in it before the shell hands it
+            // the terminal or waits for it, whichever process gets there first
+            if group.pgid == 0 {
+                group.pgid = pid;
+            }
+            unsafe {
+                libc::setpgid(pid, group.pgid);
+                if let Some(terminal) = group.terminal {
+                    libc::tcsetpgrp(terminal, group.pgid);
+                }
             }
         }
+        pids.push(pid);
+        // Only the job needs the write end, or the next job would never see the end of its input
+        drop(output);
+        input = next_input;
     }
-    pipe(&mut piped_commands)
+    Ok(pids)
 }
 
-/// This function will panic if called with an empty slice
-pub fn pipe(commands: &mut [Command]) -> i32 {
-    let end = commands.len() - 1;
-    for command in &mut commands[..end] {
-        command.stdout(Stdio::piped());
-    }
-    let mut children: Vec<Option<Child>> = vec![];
-    for command in commands {
-        if let Some(spawned) = children.last() {
-            if let Some(ref child) = *spawned {
-                if let Some(ref stdout) = child.stdout {
-                    unsafe { command.stdin(Stdio::from_raw_fd(stdout.as_raw_fd())); }
-                }
+/// Waits for the processes of the pipeline, unless it was sent to the background with `&`, in
+/// which case they are registered in the job table and this returns immediately.
+pub fn finish_pipeline(pipeline: &Pipeline, pids: Vec<Option<pid_t>>, job_control: &mut JobControl) -> i32 {
+    let command_line = pipeline.to_string();
+    // The first process that was started leads the process group, if there is one
+    let pgid = match job_control.terminal() {
+        Some(_) => pids.iter().filter_map(|&pid| pid).next().unwrap_or(0),
+        None => 0,
+    };
+    if pipeline.is_background() {
+        if pids.iter().all(Option::is_none) {
+            return NO_SUCH_COMMAND;
+        }
+        job_control.add(pids, pgid, command_line);
+        SUCCESS
+    } else {
+        job_control.wait_foreground(pids, pgid, command_line)
+    }
+}
+
+/// Runs the closure with the shell's stdout connected to a pipe and returns everything written to
+/// it, both by the shell itself and by the commands it spawns.
+pub fn capture_stdout<F: FnOnce()>(run: F) -> io::Result<String> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read_end, write_end) = (fds[0], fds[1]);
+    unsafe { libc::fcntl(read_end, libc::F_SETFD, libc::FD_CLOEXEC); }
+    // Read on another thread so that output larger than the pipe's buffer does not block
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        let mut file = unsafe { File::from_raw_fd(read_end) };
+        file.read_to_string(&mut output).map(|_| output)
+    });
+
+    let _ = stdout().flush();
+    let saved_stdout = unsafe { libc::fcntl(1, libc::F_DUPFD_CLOEXEC, 0) };
+    unsafe {
+        libc::dup2(write_end, 1);
+        libc::close(write_end);
+    }
+    CAPTURING.fetch_add(1, Ordering::SeqCst);
+    run();
+    CAPTURING.fetch_sub(1, Ordering::SeqCst);
+    let _ = stdout().flush();
+    // Restoring stdout closes the last write end, which lets the reader finish
+    unsafe {
+        libc::dup2(saved_stdout, 1);
+        libc::close(saved_stdout);
+    }
+    match reader.join() {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "failed to read the captured output")),
+    }
+}
+
+/// Runs a builtin with the shell's own descriptors redirected, so that what it writes goes where
+/// the redirections say, and restores the descriptors afterwards.
+pub fn with_redirections<F: FnOnce() -> i32>(redirections: &[Redirection], run: F) -> Result<i32, String> {
+    let mut files: Vec<File> = vec![];
+    let (first, last) = try!(redirection_tables(redirections, &mut files));
+    let _ = stdout().flush();
+    // Pairs of (redirected descriptor, copy of what it was before, or -1 if it was closed)
+    let mut saved: Vec<(RawFd, RawFd)> = vec![];
+    for (target, source) in first.into_iter().chain(last) {
+        if !saved.iter().any(|&(fd, _)| fd == target) {
+            saved.push((target, unsafe { libc::fcntl(target, libc::F_DUPFD_CLOEXEC, 10) }));
+        }
+        if source != target && unsafe { libc::dup2(source, target) } < 0 {
+            let err = io::Error::last_os_error();
+            restore(saved);
+            return Err(format!("failed to redirect {} into {}: {}", target, source, err));
+        }
+    }
+    let status = run();
+    let _ = stdout().flush();
+    restore(saved);
+    Ok(status)
+}
+
+fn restore(saved: Vec<(RawFd, RawFd)>) {
+    for (target, copy) in saved.into_iter().rev() {
+        unsafe {
+            if copy < 0 {
+                libc::close(target);
             } else {
-                // The previous command failed to spawn
-                command.stdin(Stdio::null());
+                libc::dup2(copy, target);
+                libc::close(copy);
             }
         }
-        let child = command.spawn().ok();
-        if child.is_none() {
-            println!("ion: command not found: {}", get_command_name(&command));
+    }
+}
+
+/// Opens the targets of the redirections and returns pairs of (descriptor of the command,
+/// descriptor of the shell it should be a copy of), first for the redirections of the first
+/// command of the pipeline and then for those of the last one. The redirections are applied in
+/// the order they were written, so that `> file 2>&1` sends both stdout and stderr to the file
+/// while `2>&1 > file` only sends stdout there. Redirections of stdin apply to the first command
+/// of the pipeline and every other redirection to the last one. The grammar only allows
+/// redirections at the end of a pipeline, so the commands before the last one cannot redirect
+/// their own output, as in `make 2>&1 | grep error`.
+fn redirection_tables(redirections: &[Redirection], files: &mut Vec<File>)
+    -> Result<(Vec<(RawFd, RawFd)>, Vec<(RawFd, RawFd)>), String>
+{
+    let mut first: Vec<(RawFd, RawFd)> = vec![];
+    let mut last: Vec<(RawFd, RawFd)> = vec![];
+    for redirection in redirections {
+        if redirection.fd < 0 {
+            return Err(format!("invalid file descriptor in redirection: {}", redirection));
+        }
+        let table = if redirection.fd == 0 && redirection.mode == RedirectionMode::Read {
+            &mut first
+        } else {
+            &mut last
+        };
+        let source = match redirection.target {
+            RedirectionTarget::File(ref file) => {
+                let result = match redirection.mode {
+                    RedirectionMode::Read => File::open(file),
+                    RedirectionMode::Write => File::create(file),
+                    RedirectionMode::Append => OpenOptions::new().write(true).append(true).create(true).open(file),
+                };
+                match result {
+                    Ok(file) => {
+                        let fd = file.as_raw_fd();
+                        files.push(file);
+                        fd
+                    },
+                    Err(err) => return Err(format!("failed to redirect {} into {}: {}", redirection.fd, file, err)),
+                }
+            },
+            RedirectionTarget::Descriptor(fd) => {
+                // Copy whatever the descriptor was redirected to so far, if anything
+                table.iter().rev().find(|&&(target, _)| target == fd).map_or(fd, |&(_, source)| source)
+            },
+            RedirectionTarget::HereString(ref text) => try!(feed(format!("{}\n", text), files)),
+            RedirectionTarget::HereDocument { ref body, .. } => try!(feed(body.clone(), files)),
+        };
+        table.retain(|&(target, _)| target != redirection.fd);
+        table.push((redirection.fd, source));
+    }
+    Ok((first, last))
+}
+
+/// Writes the text of a here-document or here-string to a temporary file, which is deleted as
+/// soon as it is closed, and returns a descriptor that reads it from the start. Unlike a pipe, the
+/// file needs no writer, so text larger than a pipe's buffer does not block and a fork of the
+/// shell does not keep the input of a command open.
+fn feed(text: String, files: &mut Vec<File>) -> Result<RawFd, String> {
+    let fd = unsafe {
+        let stream = libc::tmpfile();
+        if stream.is_null() {
+            return Err(format!("failed to create a file for a here-document: {}", io::Error::last_os_error()));
         }
-        children.push(child);
+        let fd = libc::fcntl(libc::fileno(stream), libc::F_DUPFD_CLOEXEC, 0);
+        libc::fclose(stream);
+        fd
+    };
+    if fd < 0 {
+        return Err(format!("failed to create a file for a here-document: {}", io::Error::last_os_error()));
     }
-    wait(&mut children)
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    if let Err(err) = file.write_all(text.as_bytes()).and_then(|_| file.seek(SeekFrom::Start(0))) {
+        return Err(format!("failed to write a here-document: {}", err));
+    }
+    files.push(file);
+    Ok(fd)
 }
 
-/// This function will panic if called with an empty vector
-fn wait(children: &mut Vec<Option<Child>>) -> i32 {
-    let end = children.len() - 1;
-    for child in children.drain(..end) {
-        if let Some(mut child) = child {
-            let _ = child.wait();
+/// Creates a pipe whose ends are closed when a command is executed, so that each command only
+/// keeps the ends it is given. Returns the read end and the write end.
+fn create_pipe() -> Result<(File, File), String> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(format!("failed to create a pipe: {}", io::Error::last_os_error()));
+    }
+    unsafe {
+        libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(fds[1], libc::F_SETFD, libc::FD_CLOEXEC);
+        Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])))
+    }
+}
+
+fn apply_redirections(command: &mut Command, table: Vec<(RawFd, RawFd)>) -> Result<(), String> {
+    let mut others: Vec<(RawFd, RawFd)> = vec![];
+    for (target, source) in table {
+        if target > 2 {
+            others.push((target, source));
+            continue;
+        }
+        // Each Stdio closes the descriptor it is given, so hand it a copy
+        let copy = unsafe { libc::fcntl(source, libc::F_DUPFD_CLOEXEC, 0) };
+        if copy < 0 {
+            return Err(format!("failed to redirect {} into {}: {}", target, source, io::Error::last_os_error()));
         }
+        let stdio = unsafe { Stdio::from_raw_fd(copy) };
+        match target {
+            0 => command.stdin(stdio),
+            1 => command.stdout(stdio),
+            _ => command.stderr(stdio),
+        };
     }
-    if let Some(mut child) = children.pop().unwrap() {
-        match child.wait() {
-            Ok(status) => {
-                if let Some(code) = status.code() {
-                    code
+    if !others.is_empty() {
+        // Descriptors other than stdin, stdout and stderr have to be set up in the child
+        command.before_exec(move || {
+            for &(target, source) in &others {
+                // dup2 is a no-op when both are the same, but the descriptor must not be closed on exec
+                let result = if source == target {
+                    unsafe { libc::fcntl(target, libc::F_SETFD, 0) }
                 } else {
-                    println!("Child ended by signal");
-                    TERMINATED
+                    unsafe { libc::dup2(source, target) }
+                };
+                if result < 0 {
+                    return Err(io::Error::last_os_error());
                 }
             }
-            Err(err) => {
-                println!("Failed to wait: {}", err);
-                100 // TODO what should we return here?
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Spawns an external command with its descriptors set up by the table and returns its pid.
+fn spawn(job: &Job, table: Vec<(RawFd, RawFd)>, group: Option<Group>) -> Option<pid_t> {
+    let mut command = job.build_command();
+    if let Err(message) = apply_redirections(&mut command, table) {
+        error(&job.command, message);
+        return None;
+    }
+    if let Some(group) = group {
+        command.before_exec(move || {
+            join(group);
+            Ok(())
+        });
+    }
+    match command.spawn() {
+        Ok(child) => Some(child.id() as pid_t),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            error(&job.command, "command not found");
+            None
+        },
+        Err(err) => {
+            error(&job.command, err);
+            None
+        }
+    }
+}
+
+/// Runs a builtin or function in a fork of the shell, with its descriptors set up by the table,
+/// and returns the pid of the fork. The fork closes the `unused` descriptors first.
+fn fork_stage<F>(job: &Job, table: &[(RawFd, RawFd)], unused: &[RawFd], group: Option<Group>, run: &mut F)
+    -> Option<pid_t>
+    where F: FnMut(&Job) -> i32
+{
+    let _ = stdout().flush();
+    match unsafe { libc::fork() } {
+        -1 => {
+            error(&job.command, format!("failed to fork: {}", io::Error::last_os_error()));
+            None
+        },
+        0 => {
+            if let Some(group) = group {
+                join(group);
+            }
+            unsafe {
+                // Like an external command, end quietly once the reader of the output is gone
+                libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+                for &fd in unused {
+                    libc::close(fd);
+                }
+            }
+            for &(target, source) in table {
+                if source != target && unsafe { libc::dup2(source, target) } < 0 {
+                    error(&job.command, format!("failed to redirect {} into {}: {}", target, source,
+                                                io::Error::last_os_error()));
+                    unsafe { libc::_exit(FAILURE) }
+                }
             }
+            let status = run(job);
+            let _ = stdout().flush();
+            unsafe { libc::_exit(status) }
+        },
+        pid => Some(pid),
+    }
+}
+
+/// Puts a child of the shell in the process group of its pipeline, which gets the terminal if the
+/// pipeline runs in the foreground, and restores the default handling of the signals that the
+/// shell ignores, so that the child can be interrupted and stopped from the terminal.
+fn join(group: Group) {
+    unsafe {
+        let pgid = if group.pgid == 0 { libc::getpid() } else { group.pgid };
+        libc::setpgid(0, pgid);
+        if let Some(terminal) = group.terminal {
+            libc::tcsetpgrp(terminal, pgid);
         }
-    } else {
-        NO_SUCH_COMMAND
     }
+    signals::default_terminal_signals();
 }
 
-fn get_command_name(command: &Command) -> String {
-    format!("{:?}", command).split('"').nth(1).unwrap_or("").to_string()
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT};
+    use libc;
+    use peg::parse;
+
+    /// Whether a test is redirecting the descriptors of the test process, which all tests share.
+    static DESCRIPTORS_LOCKED: AtomicBool = ATOMIC_BOOL_INIT;
+
+    pub struct DescriptorsLock;
+
+    impl Drop for DescriptorsLock {
+        fn drop(&mut self) {
+            DESCRIPTORS_LOCKED.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Waits until no other test redirects the descriptors of the test process, like
+    /// `capture_stdout` and `with_redirections` do, and keeps them from doing so until the lock
+    /// is dropped.
+    pub fn lock_descriptors() -> DescriptorsLock {
+        while DESCRIPTORS_LOCKED.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            thread::yield_now();
+        }
+        DescriptorsLock
+    }
+
+    /// A file in the temporary directory that is unique to the test and this run.
+    fn temporary(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("ion-pipe-{}-{}", unsafe { libc::getpid() }, name))
+    }
+
+    fn contents(path: &PathBuf) -> String {
+        let mut text = String::new();
+        File::open(path).unwrap().read_to_string(&mut text).unwrap();
+        let _ = fs::remove_file(path);
+        text
+    }
+
+    /// Writes to a descriptor directly, as a fork of the test may not take the lock of stdout.
+    fn write_fd(fd: RawFd, text: &str) {
+        unsafe { libc::write(fd, text.as_ptr() as *const libc::c_void, text.len()); }
+    }
+
+    fn read_fd(fd: RawFd) -> String {
+        let mut bytes = vec![];
+        let mut buffer = [0u8; 64];
+        loop {
+            let count = unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if count <= 0 {
+                break;
+            }
+            bytes.extend_from_slice(&buffer[..count as usize]);
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
+    /// Runs the pipeline, with the jobs named in `internal` run by `run` like builtins, and returns
+    /// its exit status.
+    fn run_pipeline<F: FnMut(&Job) -> i32>(code: &str, internal: &[&str], run: F) -> i32 {
+        let pipeline = parse(code).unwrap().remove(0);
+        let internal: Vec<bool> = pipeline.jobs.iter().map(|job| internal.contains(&job.command.as_str())).collect();
+        let pids = spawn_pipeline(&pipeline, &internal, None, run).unwrap();
+        finish_pipeline(&pipeline, pids, &mut JobControl::default())
+    }
+
+    #[test]
+    fn builtin_in_a_pipeline() {
+        let output = temporary("builtin");
+        let code = format!("echo hello world | tr a-z A-Z > {}", output.display());
+        let status = run_pipeline(&code, &["echo"], |job: &Job| {
+            write_fd(1, &format!("{}\n", job.args[1..].join(" ")));
+            SUCCESS
+        });
+        assert_eq!(SUCCESS, status);
+        assert_eq!("HELLO WORLD\n", contents(&output));
+    }
+
+    #[test]
+    fn function_as_the_last_stage() {
+        let output = temporary("function");
+        let code = format!("seq 3 | reverse > {}", output.display());
+        let status = run_pipeline(&code, &["reverse"], |_: &Job| {
+            let input = read_fd(0);
+            let lines: Vec<&str> = input.lines().rev().collect();
+            write_fd(1, &format!("{}\n", lines.join("\n")));
+            3
+        });
+        assert_eq!(3, status);
+        assert_eq!("3\n2\n1\n", contents(&output));
+    }
+
+    #[test]
+    fn builtin_with_redirections() {
+        let _lock = lock_descriptors();
+        let input = temporary("input");
+        let output = temporary("output");
+        File::create(&input).unwrap().write_all(b"first\nsecond\n").unwrap();
+        let code = format!("read < {} > {}", input.display(), output.display());
+        let pipeline = parse(&code).unwrap().remove(0);
+        let status = with_redirections(&pipeline.redirections, || {
+            let line = read_fd(0);
+            write_fd(1, &line.to_uppercase());
+            FAILURE
+        });
+        assert_eq!(Ok(FAILURE), status);
+        assert_eq!("FIRST\nSECOND\n", contents(&output));
+        let _ = fs::remove_file(&input);
+    }
+
+    #[test]
+    fn failed_redirection_runs_nothing() {
+        let _lock = lock_descriptors();
+        let code = "read < /nonexistent/input";
+        let pipeline = parse(code).unwrap().remove(0);
+        let mut ran = false;
+        assert!(with_redirections(&pipeline.redirections, || { ran = true; SUCCESS }).is_err());
+        assert!(!ran);
+    }
 }
@@ -0,0 +1,166 @@
+//! Quoting of the words of a command line
+//!
+//! The parser keeps each word as it was written, quotes and backslashes included, so that the
+//! pipelines of blocks and functions keep their quoting until they are run. When a word is
+//! expanded it is first split into parts that are unquoted, double quoted or single quoted.
+
+use std::cmp;
+
+use super::substitution::find_closing_paren;
+use super::variables::Variables;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Quoting {
+    /// Variables, substitutions, `~` and globs are expanded
+    Unquoted,
+    /// Variables and substitutions are expanded, the result stays a single argument
+    Double,
+    /// Taken literally, as are characters escaped with a backslash
+    Single,
+}
+
+/// A run of a word that is quoted the same way.
+#[derive(Debug, PartialEq)]
+pub struct Part {
+    pub text: String,
+    pub quoting: Quoting,
+}
+
+fn push(parts: &mut Vec<Part>, quoting: Quoting, text: &str) {
+    if let Some(last) = parts.last_mut() {
+        if last.quoting == quoting {
+            last.text.push_str(text);
+            return;
+        }
+    }
+    parts.push(Part { text: text.to_string(), quoting: quoting });
+}
+
+/// Splits a word into its differently quoted parts, removing the quotes and the backslashes of
+/// escapes. Outside of quotes a backslash escapes any character. Inside double quotes it escapes
+/// `"`, `\`, `$` and `` ` ``, `\n` and `\t` are a newline and a tab, and it is kept before any
+/// other character. `$(...)` and `${...}` are kept whole, along with the quotes inside them.
+pub fn split_quotes(word: &str) -> Vec<Part> {
+    split(word, false)
+}
+
+/// Splits the body of a here-document like the inside of double quotes, except that quotes are
+/// not special and a backslash only escapes `\`, `$` and `` ` ``.
+pub fn split_here_document(body: &str) -> Vec<Part> {
+    split(body, true)
+}
+
+fn split(word: &str, here_document: bool) -> Vec<Part> {
+    let mut parts = vec![];
+    let mut double = here_document;
+    let mut index = 0;
+    while index < word.len() {
+        let rest = &word[index..];
+        let character = rest.chars().next().unwrap();
+        let quoting = if double { Quoting::Double } else { Quoting::Unquoted };
+        match character {
+            '\'' if !double => {
+                let end = rest[1..].find('\'').map_or(rest.len(), |end| end + 1);
+                push(&mut parts, Quoting::Single, &rest[1..end]);
+                index += cmp::min(end + 1, rest.len());
+            },
+            '"' if !here_document => {
+                // `""` is an empty argument rather than no argument at all
+                push(&mut parts, Quoting::Double, "");
+                double = !double;
+                index += 1;
+            },
+            '\\' => {
+                let escaped = rest[1..].chars().next();
+                match escaped {
+                    None => push(&mut parts, Quoting::Single, "\\"),
+                    // A backslash before a newline continues the line
+                    Some('\n') => (),
+                    Some(escaped) if !double || "\\$`".contains(escaped) || (escaped == '"' && !here_document) => {
+                        push(&mut parts, Quoting::Single, &escaped.to_string())
+                    },
+                    Some('n') if !here_document => push(&mut parts, Quoting::Single, "\n"),
+                    Some('t') if !here_document => push(&mut parts, Quoting::Single, "\t"),
+                    Some(escaped) => push(&mut parts, Quoting::Single, &format!("\\{}", escaped)),
+                }
+                index += 1 + escaped.map_or(0, |escaped| escaped.len_utf8());
+            },
+            '$' if rest[1..].starts_with('(') || rest[1..].starts_with('{') => {
+                let end = if rest[1..].starts_with('(') {
+                    find_closing_paren(word, index + 2)
+                } else {
+                    Variables::find_closing_brace(word, index + 2)
+                };
+                let end = end.map_or(word.len(), |end| end + 1);
+                push(&mut parts, quoting, &word[index..end]);
+                index = end;
+            },
+            _ => {
+                push(&mut parts, quoting, &rest[..character.len_utf8()]);
+                index += character.len_utf8();
+            },
+        }
+    }
+    parts
+}
+
+/// Whether the text has a character that makes it a glob pattern.
+pub fn is_glob(text: &str) -> bool {
+    text.contains(|character| character == '?' || character == '*' || character == '[')
+}
+
+/// Quotes the text so that it is read back as a single argument with exactly this text.
+pub fn escape(text: &str) -> String {
+    let plain = |character: char| character.is_alphanumeric() || "_-./=,:+%".contains(character);
+    if !text.is_empty() && text.chars().all(plain) {
+        text.to_string()
+    } else {
+        format!("'{}'", text.replace("'", "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(text: &str, quoting: Quoting) -> Part {
+        Part { text: text.to_string(), quoting: quoting }
+    }
+
+    #[test]
+    fn adjacent_quoted_parts() {
+        assert_eq!(vec![part("foo", Quoting::Unquoted), part("bar $baz", Quoting::Double), part("$x", Quoting::Single)],
+                   split_quotes("foo\"bar $baz\"'$x'"));
+        assert_eq!(vec![part("", Quoting::Double)], split_quotes("\"\""));
+    }
+
+    #[test]
+    fn backslash_escapes() {
+        assert_eq!(vec![part("a", Quoting::Unquoted), part(" *", Quoting::Single)], split_quotes("a\\ \\*"));
+        assert_eq!(vec![part("say ", Quoting::Double), part("\"$", Quoting::Single), part("x", Quoting::Double),
+                        part("\n\\d", Quoting::Single), part("", Quoting::Double)],
+                   split_quotes("\"say \\\"\\$x\\n\\d\""));
+    }
+
+    #[test]
+    fn substitutions_keep_their_quotes() {
+        assert_eq!(vec![part("$(echo \"a b\")", Quoting::Double), part("-${x:-'y'}", Quoting::Unquoted)],
+                   split_quotes("\"$(echo \"a b\")\"-${x:-'y'}"));
+    }
+
+    #[test]
+    fn here_document_bodies() {
+        assert_eq!(vec![part("\"$x\" '", Quoting::Double), part("$", Quoting::Single), part("y", Quoting::Double),
+                        part("\\n\\\"", Quoting::Single)],
+                   split_here_document("\"$x\" '\\$y\\n\\\""));
+    }
+
+    #[test]
+    fn escape_quotes_when_needed() {
+        assert_eq!("src/main.rs", escape("src/main.rs"));
+        assert_eq!("'a b'", escape("a b"));
+        assert_eq!("'it'\\''s'", escape("it's"));
+        assert_eq!("''", escape(""));
+        assert_eq!("'$HOME'", escape("$HOME"));
+    }
+}
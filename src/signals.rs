@@ -0,0 +1,129 @@
+//! Names of signals, the signals an interactive shell leaves to the foreground pipeline, and the
+//! signals the shell catches for `trap`
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use libc::{self, c_int, sighandler_t};
+
+/// The signals that have a name, in the order of their numbers on Linux.
+pub const SIGNALS: &'static [(c_int, &'static str)] = &[
+    (libc::SIGHUP, "HUP"),
+    (libc::SIGINT, "INT"),
+    (libc::SIGQUIT, "QUIT"),
+    (libc::SIGILL, "ILL"),
+    (libc::SIGTRAP, "TRAP"),
+    (libc::SIGABRT, "ABRT"),
+    (libc::SIGBUS, "BUS"),
+    (libc::SIGFPE, "FPE"),
+    (libc::SIGKILL, "KILL"),
+    (libc::SIGUSR1, "USR1"),
+    (libc::SIGSEGV, "SEGV"),
+    (libc::SIGUSR2, "USR2"),
+    (libc::SIGPIPE, "PIPE"),
+    (libc::SIGALRM, "ALRM"),
+    (libc::SIGTERM, "TERM"),
+    (libc::SIGCHLD, "CHLD"),
+    (libc::SIGCONT, "CONT"),
+    (libc::SIGSTOP, "STOP"),
+    (libc::SIGTSTP, "TSTP"),
+    (libc::SIGTTIN, "TTIN"),
+    (libc::SIGTTOU, "TTOU"),
+    (libc::SIGURG, "URG"),
+    (libc::SIGXCPU, "XCPU"),
+    (libc::SIGXFSZ, "XFSZ"),
+    (libc::SIGVTALRM, "VTALRM"),
+    (libc::SIGPROF, "PROF"),
+    (libc::SIGWINCH, "WINCH"),
+    (libc::SIGIO, "IO"),
+    (libc::SIGSYS, "SYS"),
+];
+
+/// The signals sent by the terminal, which an interactive shell ignores so that keys like Ctrl-C
+/// and Ctrl-Z only reach the foreground pipeline.
+const TERMINAL_SIGNALS: [c_int; 5] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+
+/// The caught signals that were received since they were last taken, one bit for each signal.
+static PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The name of the signal, like `SIGINT`.
+pub fn name(signal: c_int) -> String {
+    match SIGNALS.iter().find(|&&(number, _)| number == signal) {
+        Some(&(_, name)) => format!("SIG{}", name),
+        None => format!("signal {}", signal),
+    }
+}
+
+/// The number of the signal given by its name, with or without `SIG` and in any case, or by its
+/// number.
+pub fn number(name: &str) -> Option<c_int> {
+    if let Ok(number) = name.parse::<c_int>() {
+        return SIGNALS.iter().find(|&&(signal, _)| signal == number).map(|&(signal, _)| signal);
+    }
+    let name = name.to_uppercase();
+    let name = name.trim_left_matches("SIG");
+    SIGNALS.iter().find(|&&(_, signal_name)| signal_name == name).map(|&(signal, _)| signal)
+}
+
+/// The exit status of a process that was ended or stopped by the signal.
+pub fn status(signal: c_int) -> i32 {
+    128 + signal
+}
+
+/// Ignores the signals sent by the terminal.
+pub fn ignore_terminal_signals() {
+    for &signal in &TERMINAL_SIGNALS {
+        handle(signal, libc::SIG_IGN);
+    }
+}
+
+/// Restores the default handling of the signals sent by the terminal, which a child of an
+/// interactive shell would otherwise inherit as ignored.
+pub fn default_terminal_signals() {
+    for &signal in &TERMINAL_SIGNALS {
+        handle(signal, libc::SIG_DFL);
+    }
+}
+
+extern "C" fn remember(signal: c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Catches the signal, which is then remembered until `take_pending` is called, and returns how it
+/// was handled before.
+pub fn catch(signal: c_int) -> sighandler_t {
+    handle(signal, remember as sighandler_t)
+}
+
+/// Sets how the signal is handled, e.g. to `SIG_DFL` or `SIG_IGN`, and returns how it was handled
+/// before.
+pub fn handle(signal: c_int, handler: sighandler_t) -> sighandler_t {
+    unsafe { libc::signal(signal, handler) }
+}
+
+/// The caught signals that were received since the last call, in the order of their numbers.
+pub fn take_pending() -> Vec<c_int> {
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    SIGNALS.iter().map(|&(signal, _)| signal).filter(|&signal| pending & (1 << signal) != 0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+
+    #[test]
+    fn names_and_numbers() {
+        assert_eq!("SIGINT", name(libc::SIGINT));
+        assert_eq!("signal 99", name(99));
+        assert_eq!(Some(libc::SIGTERM), number("TERM"));
+        assert_eq!(Some(libc::SIGTERM), number("sigterm"));
+        assert_eq!(Some(libc::SIGHUP), number("1"));
+        assert_eq!(None, number("NOPE"));
+        assert_eq!(None, number("99"));
+    }
+
+    #[test]
+    fn status_of_a_signal() {
+        assert_eq!(130, status(libc::SIGINT));
+        assert_eq!(143, status(libc::SIGTERM));
+    }
+}
@@ -1,4 +1,4 @@
 pub const SUCCESS: i32 = 0;
 pub const FAILURE: i32 = -1;
+pub const SYNTAX_ERROR: i32 = 2;
 pub const NO_SUCH_COMMAND: i32 = 127;
-pub const TERMINATED: i32 = 143;
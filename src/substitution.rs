@@ -0,0 +1,121 @@
+//! Splitting of words into plain text, `$(command)` substitutions and `$((expression))`
+//! arithmetic
+
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    /// Text in which variables still have to be expanded
+    Text(&'a str),
+    /// The command inside `$(...)`, whose output replaces the substitution
+    Command(&'a str),
+    /// The expression inside `$((...))`, whose value replaces the substitution
+    Arithmetic(&'a str),
+}
+
+/// Returns the index of the parenthesis closing the one just before `start`, skipping over nested
+/// parentheses, quoted strings and escaped characters.
+pub fn find_closing_paren(word: &str, start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (index, character) in word[start..].char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        if character == '\\' && quote != Some('\'') {
+            escaped = true;
+            continue;
+        }
+        if let Some(quote_character) = quote {
+            if character == quote_character {
+                quote = None;
+            }
+            continue;
+        }
+        match character {
+            '"' | '\'' => quote = Some(character),
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(start + index),
+            ')' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Splits a word into the commands of its `$(...)` substitutions, the expressions of its `$((...))`
+/// substitutions and the text around them. A substitution preceded by a backslash or missing its
+/// closing parenthesis is kept as text.
+pub fn split_substitutions(word: &str) -> Vec<Segment> {
+    let mut segments = vec![];
+    let mut text_start = 0;
+    let mut index = 0;
+    while let Some(offset) = word[index..].find("$(") {
+        let dollar = index + offset;
+        let escaped = word[..dollar].ends_with('\\');
+        match find_closing_paren(word, dollar + 2) {
+            Some(end) if !escaped => {
+                if text_start < dollar {
+                    segments.push(Segment::Text(&word[text_start..dollar]));
+                }
+                // `$((a) | b)` is a command substituting a subshell, not arithmetic
+                match find_closing_paren(word, dollar + 3) {
+                    Some(inner) if word[dollar + 2..].starts_with('(') && inner + 1 == end => {
+                        segments.push(Segment::Arithmetic(&word[dollar + 3..inner]));
+                    },
+                    _ => segments.push(Segment::Command(&word[dollar + 2..end])),
+                }
+                index = end + 1;
+                text_start = index;
+            },
+            _ => index = dollar + 1,
+        }
+    }
+    if text_start < word.len() {
+        segments.push(Segment::Text(&word[text_start..]));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_without_substitution() {
+        assert_eq!(vec![Segment::Text("$HOME/file")], split_substitutions("$HOME/file"));
+    }
+
+    #[test]
+    fn substitution_inside_word() {
+        assert_eq!(vec![Segment::Text("branch="),
+                        Segment::Command("git rev-parse --abbrev-ref HEAD"),
+                        Segment::Text(".log")],
+                   split_substitutions("branch=$(git rev-parse --abbrev-ref HEAD).log"));
+    }
+
+    #[test]
+    fn nested_and_quoted_substitution() {
+        assert_eq!(vec![Segment::Command("echo $(cat \")\" | wc -l) (a)")],
+                   split_substitutions("$(echo $(cat \")\" | wc -l) (a))"));
+    }
+
+    #[test]
+    fn arithmetic_substitution() {
+        assert_eq!(vec![Segment::Text("x="), Segment::Arithmetic("(x + 1) * 2")],
+                   split_substitutions("x=$(((x + 1) * 2))"));
+        assert_eq!(vec![Segment::Command("(echo a) | cat")], split_substitutions("$((echo a) | cat)"));
+    }
+
+    #[test]
+    fn escaped_parenthesis_in_substitution() {
+        assert_eq!(vec![Segment::Command("echo \\) \"\\\")\"")],
+                   split_substitutions("$(echo \\) \"\\\")\")"));
+    }
+
+    #[test]
+    fn escaped_or_unclosed_substitution() {
+        assert_eq!(vec![Segment::Text("\\$(echo a)")], split_substitutions("\\$(echo a)"));
+        assert_eq!(vec![Segment::Text("$(echo a")], split_substitutions("$(echo a"));
+    }
+}
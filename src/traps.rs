@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::mem;
+use libc::{self, c_int, sighandler_t};
+
+use super::diagnostics::error;
+use super::quoting::escape;
+use super::signals::{self, SIGNALS};
+use super::status::{SUCCESS, FAILURE};
+
+/// Something that code can be run on with `trap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Event {
+    /// The shell exits
+    Exit,
+    /// A pipeline that is not a condition fails
+    Error,
+    Signal(c_int),
+}
+
+impl Event {
+    /// Parses `EXIT` or `0`, `ERR`, or the name or number of a signal.
+    pub fn parse(name: &str) -> Option<Event> {
+        match name.to_uppercase().as_str() {
+            "EXIT" | "0" => Some(Event::Exit),
+            "ERR" => Some(Event::Error),
+            _ => signals::number(name).map(Event::Signal),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match *self {
+            Event::Exit => "EXIT".to_string(),
+            Event::Error => "ERR".to_string(),
+            Event::Signal(signal) => signals::name(signal),
+        }
+    }
+}
+
+/// The code registered for each event with `trap`.
+pub struct Traps {
+    /// An empty action ignores the signal
+    actions: BTreeMap<Event, String>,
+    /// How the trapped signals were handled before they were trapped, which `trap -` restores
+    original: BTreeMap<c_int, sighandler_t>,
+    /// Whether the code of a trap is running, during which no other trap is run
+    pub running: bool,
+    /// Whether the `ERR` trap is not run, as while the condition of an `if` or `while` is
+    /// evaluated or the output of a command substitution is captured
+    pub ignore_errors: bool,
+    /// Whether the failure of the last pipeline was already dealt with by a pipeline inside it,
+    /// like the last one of the body of a function, so that the `ERR` trap is run only once
+    pub error_reported: bool,
+}
+
+impl Default for Traps {
+    fn default() -> Traps {
+        Traps {
+            actions: BTreeMap::new(),
+            original: BTreeMap::new(),
+            running: false,
+            ignore_errors: false,
+            error_reported: false,
+        }
+    }
+}
+
+impl Traps {
+    /// The code to run on the event, if there is any.
+    pub fn action(&self, event: Event) -> Option<String> {
+        match self.actions.get(&event) {
+            Some(action) if !action.is_empty() => Some(action.clone()),
+            _ => None,
+        }
+    }
+
+    /// Removes every trap in a fork of the shell: trapped signals are handled the default way
+    /// again, while ignored signals stay ignored.
+    pub fn clear(&mut self) {
+        for (event, action) in mem::replace(&mut self.actions, BTreeMap::new()) {
+            if let Event::Signal(signal) = event {
+                if !action.is_empty() {
+                    signals::handle(signal, libc::SIG_DFL);
+                }
+            }
+        }
+        self.original.clear();
+    }
+
+    fn set(&mut self, event: Event, action: &str) {
+        if let Event::Signal(signal) = event {
+            let previous = if action.is_empty() {
+                signals::handle(signal, libc::SIG_IGN)
+            } else {
+                signals::catch(signal)
+            };
+            self.original.entry(signal).or_insert(previous);
+        }
+        self.actions.insert(event, action.to_string());
+    }
+
+    fn reset(&mut self, event: Event) {
+        if let Event::Signal(signal) = event {
+            if let Some(original) = self.original.remove(&signal) {
+                signals::handle(signal, original);
+            }
+        }
+        self.actions.remove(&event);
+    }
+
+    /// Sets, resets or prints the code run on signals and on the `EXIT` and `ERR` events.
+    pub fn trap<I: IntoIterator>(&mut self, args: I) -> i32
+        where I::Item: AsRef<str>
+    {
+        let args: Vec<String> = args.into_iter().skip(1).map(|arg| arg.as_ref().to_string()).collect();
+        if args.is_empty() {
+            for (event, action) in &self.actions {
+                println!("trap -- {} {}", escape(action), event.name());
+            }
+            return SUCCESS;
+        }
+        if args[0] == "-l" {
+            for &(signal, name) in SIGNALS {
+                println!("{:2}) SIG{}", signal, name);
+            }
+            return SUCCESS;
+        }
+        if args.len() < 2 {
+            error("trap", "missing signal");
+            return FAILURE;
+        }
+        let mut events = vec![];
+        for name in &args[1..] {
+            match Event::parse(name) {
+                Some(Event::Signal(signal)) if signal == libc::SIGKILL || signal == libc::SIGSTOP => {
+                    error("trap", format!("{} cannot be trapped", signals::name(signal)));
+                    return FAILURE;
+                },
+                Some(event) => events.push(event),
+                None => {
+                    error("trap", format!("invalid signal: {}", name));
+                    return FAILURE;
+                }
+            }
+        }
+        for event in events {
+            if args[0] == "-" {
+                self.reset(event);
+            } else {
+                self.set(event, &args[0]);
+            }
+        }
+        SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+
+    #[test]
+    fn parse_events() {
+        assert_eq!(Some(Event::Exit), Event::parse("EXIT"));
+        assert_eq!(Some(Event::Exit), Event::parse("0"));
+        assert_eq!(Some(Event::Error), Event::parse("err"));
+        assert_eq!(Some(Event::Signal(libc::SIGUSR1)), Event::parse("USR1"));
+        assert_eq!(Some(Event::Signal(libc::SIGTERM)), Event::parse("SIGTERM"));
+        assert_eq!(None, Event::parse("NOPE"));
+    }
+
+    #[test]
+    fn set_and_reset() {
+        let mut traps = Traps::default();
+        assert_eq!(SUCCESS, traps.trap(vec!["trap", "echo bye", "EXIT", "ERR"]));
+        assert_eq!(Some("echo bye".to_string()), traps.action(Event::Exit));
+        assert_eq!(SUCCESS, traps.trap(vec!["trap", "-", "EXIT"]));
+        assert_eq!(None, traps.action(Event::Exit));
+        assert_eq!(Some("echo bye".to_string()), traps.action(Event::Error));
+        assert_eq!(FAILURE, traps.trap(vec!["trap", "echo", "KILL"]));
+        assert_eq!(FAILURE, traps.trap(vec!["trap", "echo"]));
+    }
+}
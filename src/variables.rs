@@ -1,20 +1,33 @@
+use std::cmp;
 use std::collections::BTreeMap;
 use std::io::{stdout, Write};
 use std::env;
+use std::mem;
 
-use super::peg::{Pipeline, Job};
 use super::input_editor::readln;
+use super::diagnostics::error;
 use super::status::{SUCCESS, FAILURE};
 
+use glob::Pattern;
 use regex::Regex;
 
 pub struct Variables {
     variables: BTreeMap<String, String>,
+    arrays: BTreeMap<String, Vec<String>>,
+    /// The positional arguments, starting with `$0`
+    args: Vec<String>,
+    /// Whether `expand_string_checked` fails on an unset variable, as with `set -u`
+    pub nounset: bool,
 }
 
 impl Default for Variables {
     fn default() -> Variables {
-        Variables { variables: BTreeMap::new() }
+        Variables {
+            variables: BTreeMap::new(),
+            arrays: BTreeMap::new(),
+            args: vec![],
+            nounset: false,
+        }
     }
 }
 
@@ -26,7 +39,7 @@ impl Variables {
         for arg in args.into_iter().skip(1) {
             print!("{}=", arg.as_ref().trim());
             if let Err(message) = out.flush() {
-                println!("{}: Failed to flush stdout", message);
+                error("read", format!("failed to flush stdout: {}", message));
                 return FAILURE;
             }
             if let Some(value) = readln() {
@@ -39,22 +52,34 @@ impl Variables {
     pub fn let_<I: IntoIterator>(&mut self, args: I) -> i32
         where I::Item: AsRef<str>
     {
+        let args: Vec<String> = args.into_iter().map(|arg| arg.as_ref().to_string()).collect();
+        if let Some((key, elements)) = Variables::parse_array_assignment(&args) {
+            if !Variables::is_valid_variable_name(&key) {
+                error("let", format!("invalid variable name: {}", key));
+                return FAILURE;
+            }
+            self.set_array(&key, elements);
+            return SUCCESS;
+        }
         match Variables::parse_assignment(args) {
             (Some(key), Some(value)) => {
                 if !Variables::is_valid_variable_name(&key) {
-                    println!("Invalid variable name");
+                    error("let", format!("invalid variable name: {}", key));
                     return FAILURE;
                 }
                 self.variables.insert(key.to_string(), value.to_string());
             },
-            (Some(_), None) => {
-                println!("Please provide a value for the variable");
+            (Some(key), None) => {
+                error("let", format!("missing value for {}", key));
                 return FAILURE;
             },
             _ => {
                 for (key, value) in &self.variables {
                     println!("{}={}", key, value);
                 }
+                for (key, elements) in &self.arrays {
+                    println!("{}=[{}]", key, elements.join(" "));
+                }
             }
         }
         SUCCESS
@@ -65,12 +90,12 @@ impl Variables {
     {
         let args = args.into_iter().collect::<Vec<I::Item>>();
         if args.len() <= 1 {
-            println!("You must specify a variable name");
+            error("drop", "missing variable name");
             return FAILURE;
         }
         for variable in args.iter().skip(1) {
             if let None = self.unset_var(variable.as_ref()) {
-                println!("Undefined variable: {}", variable.as_ref());
+                error("drop", format!("undefined variable: {}", variable.as_ref()));
                 return FAILURE;
             }
         }
@@ -79,6 +104,7 @@ impl Variables {
 
     pub fn set_var(&mut self, name: &str, value: &str) {
         if !name.is_empty() {
+            self.arrays.remove(name);
             if value.is_empty() {
                 self.variables.remove(&name.to_string());
             } else {
@@ -88,11 +114,88 @@ impl Variables {
     }
 
     pub fn get_var(&self, name: &str) -> Option<String> {
-        self.variables.get(name).cloned().or(env::var(name).ok())
+        match name {
+            "#" => Some(self.args.len().saturating_sub(1).to_string()),
+            "@" => Some(self.args().join(" ")),
+            _ => {
+                if let Ok(index) = name.parse::<usize>() {
+                    return self.args.get(index).cloned();
+                }
+                self.variables.get(name).cloned()
+                    .or_else(|| self.arrays.get(name).map(|elements| elements.join(" ")))
+                    .or(env::var(name).ok())
+            }
+        }
+    }
+
+    pub fn set_array(&mut self, name: &str, elements: Vec<String>) {
+        if !name.is_empty() {
+            self.variables.remove(name);
+            self.arrays.insert(name.to_string(), elements);
+        }
+    }
+
+    pub fn get_array(&self, name: &str) -> Option<&Vec<String>> {
+        self.arrays.get(name)
+    }
+
+    /// Replaces the positional arguments, the first of which is `$0`, and returns the previous
+    /// ones.
+    pub fn set_args(&mut self, args: Vec<String>) -> Vec<String> {
+        mem::replace(&mut self.args, args)
+    }
+
+    /// The positional arguments after `$0`, which `$@` expands to.
+    pub fn args(&self) -> &[String] {
+        if self.args.is_empty() { &self.args } else { &self.args[1..] }
+    }
+
+    /// The names of all shell and environment variables, sorted and without duplicates.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.variables.keys().chain(self.arrays.keys()).cloned()
+                                         .chain(env::vars().map(|(name, _)| name)).collect();
+        names.sort();
+        names.dedup();
+        names
     }
 
     pub fn unset_var(&mut self, name: &str) -> Option<String> {
-        self.variables.remove(name)
+        self.variables.remove(name).or_else(|| self.arrays.remove(name).map(|elements| elements.join(" ")))
+    }
+
+    /// Whether the arguments of `let` assign an array, as in `let files = [a b c]`.
+    pub fn is_array_assignment(args: &[String]) -> bool {
+        Variables::parse_array_assignment(args).is_some()
+    }
+
+    /// Parses `name = [a b c]`, whose arguments may be split around the `=` in any way, into the
+    /// name of the array and its elements. Returns `None` if the value is not an array.
+    fn parse_array_assignment(args: &[String]) -> Option<(String, Vec<String>)> {
+        let mut key = String::new();
+        let mut iter = args.iter().skip(1);
+        let mut value = vec![];
+        while let Some(arg) = iter.next() {
+            match arg.find('=') {
+                Some(equals) => {
+                    key.push_str(&arg[..equals]);
+                    if equals + 1 < arg.len() {
+                        value.push(arg[equals + 1..].to_string());
+                    }
+                    value.extend(iter.cloned());
+                    break;
+                },
+                None => key.push_str(arg),
+            }
+        }
+        let is_array = value.first().map_or(false, |first| first.starts_with('[')) &&
+                       value.last().map_or(false, |last| last.ends_with(']'));
+        if !is_array || key.is_empty() {
+            return None;
+        }
+        let last = value.len() - 1;
+        value[0].remove(0);
+        value[last].pop();
+        Some((key, value.into_iter().filter(|element| !element.is_empty()).collect()))
     }
 
     fn parse_assignment<I: IntoIterator>(args: I) -> (Option<String>, Option<String>)
@@ -110,7 +213,7 @@ impl Variables {
         match Variables::parse_assignment(args) {
             (Some(key), Some(value)) => {
                 if !Variables::is_valid_variable_name(&key) {
-                    println!("Invalid variable name");
+                    error("export", format!("invalid variable name: {}", key));
                     return FAILURE;
                 }
                 env::set_var(key, value);
@@ -119,40 +222,21 @@ impl Variables {
                 if let Some(local_value) = self.get_var(&key) {
                     env::set_var(key, local_value);
                 } else {
-                    println!("Unknown variable: {}", &key);
+                    error("export", format!("undefined variable: {}", key));
                     return FAILURE;
                 }
             },
             _ => {
-                println!("Usage: export KEY=VALUE");
+                error("export", "usage: export KEY=VALUE");
                 return FAILURE;
             }
         }
         SUCCESS
     }
 
-    pub fn expand_pipeline(&self, pipeline: &Pipeline) -> Pipeline {
-        // TODO don't copy everything
-        // TODO ugh, I made it worse
-        Pipeline::new(
-            pipeline.jobs.iter().map(|job| {self.expand_job(job)}).collect(),
-            pipeline.stdin.clone(),
-            pipeline.stdout.clone())
-    }
-
-    pub fn expand_job(&self, job: &Job) -> Job {
-        // TODO don't copy everything
-        Job::new(job.args
-                    .iter()
-                    .map(|original: &String| self.expand_string(&original))
-                    .collect(),
-                 job.background)
-    }
-
+    /// Replaces the bytes from `start` up to and including `end` with the replacement.
     fn replace_substring(string: &mut String, start: usize, end: usize, replacement: &str) {
-        let string_start = string.chars().take(start).collect::<String>();
-        let string_end = string.chars().skip(end+1).collect::<String>();
-        *string = string_start + replacement + &string_end;
+        *string = string[..start].to_string() + replacement + &string[end+1..];
     }
 
     pub fn is_valid_variable_character(c: char) -> bool {
@@ -192,42 +276,351 @@ impl Variables {
         word
     }
 
-    pub fn expand_string<'a>(&'a self, original: &'a str) -> String {
+    pub fn expand_string(&self, original: &str) -> String {
+        self.expand_variables(&self.tilde_expansion(original.to_owned()))
+    }
+
+    /// Expands the variables and parameters of the string, but not a leading `~`.
+    pub fn expand_variables(&self, original: &str) -> String {
+        self.substitute_variables(original, &mut None)
+    }
+
+    /// Expands the variables and parameters of the string, and sets `unset` to the name of the
+    /// first one that is unset, which expands to nothing.
+    fn substitute_variables(&self, original: &str, unset: &mut Option<String>) -> String {
         let mut new = original.to_owned();
-        new = self.tilde_expansion(new);
         let mut replacements: Vec<(usize, usize, String)> = vec![];
+        // The end of the last replacement, as the index of an array may contain variables
+        let mut replaced_until = 0;
         for (n, _) in original.match_indices('$') {
-            if n > 0 {
-                if let Some(c) = original.chars().nth(n-1) {
-                    if c == '\\' {
-                        continue;
+            if n < replaced_until {
+                continue;
+            }
+            if original[..n].ends_with('\\') {
+                continue;
+            }
+            // `${parameter}` may apply an operator to the value of the parameter
+            if original[n+1..].starts_with('{') {
+                if let Some(close) = Variables::find_closing_brace(original, n + 2) {
+                    let (name, operator) = Variables::split_parameter(&original[n+2..close]);
+                    if operator.is_empty() && unset.is_none() && self.get_var(name).is_none() {
+                        *unset = Some(name.to_string());
                     }
+                    let value = self.expand_parameter(&original[n+2..close]).unwrap_or(String::new());
+                    replacements.push((n, close, value));
+                    replaced_until = close + 1;
                 }
+                continue;
             }
-            let mut var_name = "".to_owned();
-            for (i, c) in original.char_indices().skip(n+1) { // skip the dollar sign
-                if Variables::is_valid_variable_character(c) {
-                    var_name.push(c);
-                    if i == original.len() - 1 {
-                        replacements.push((n, i, var_name.clone()));
-                        break;
+            // `$#`, `$@` and positional arguments like `$1` are a single character, unless `$#` is
+            // followed by the name of an array, whose length it then is
+            match original[n+1..].chars().next() {
+                Some('#') => {
+                    let name: String = original[n+2..].chars().take_while(|&c| Variables::is_valid_variable_character(c)).collect();
+                    if let Some(array) = self.arrays.get(&name) {
+                        replacements.push((n, n + 1 + name.len(), array.len().to_string()));
+                        replaced_until = n + 2 + name.len();
+                    } else {
+                        replacements.push((n, n+1, self.get_var("#").unwrap_or(String::new())));
+                    }
+                    continue;
+                },
+                Some(special) if special == '@' || special.is_digit(10) => {
+                    let value = self.get_var(&special.to_string());
+                    if value.is_none() && unset.is_none() {
+                        *unset = Some(special.to_string());
+                    }
+                    replacements.push((n, n+1, value.unwrap_or(String::new())));
+                    continue;
+                },
+                _ => (),
+            }
+            if n + 1 == original.len() {
+                continue;
+            }
+            let var_name: String = original[n+1..].chars().take_while(|&c| Variables::is_valid_variable_character(c)).collect();
+            let end = n + var_name.len();
+            // `$array[index]` is an element and `$array[start..end]` a slice of an array
+            if let Some(array) = self.arrays.get(&var_name) {
+                if original[end+1..].starts_with('[') {
+                    if let Some(close) = original[end+1..].find(']') {
+                        let selector = self.expand_string(&original[end+2..end+1+close]);
+                        if let Some(elements) = Variables::select(array, &selector) {
+                            replacements.push((n, end + 1 + close, elements.join(" ")));
+                            replaced_until = end + 2 + close;
+                            continue;
+                        }
                     }
-                } else {
-                    replacements.push((n, i-1, var_name.clone()));
-                    break;
                 }
             }
+            let value = self.get_var(&var_name);
+            if value.is_none() && unset.is_none() && !var_name.is_empty() {
+                *unset = Some(var_name.clone());
+            }
+            replacements.push((n, end, value.unwrap_or(String::new())));
         }
 
-        for &(start, end, ref var_name) in replacements.iter().rev() {
-            if let Some(value) = self.get_var(var_name) {
-                Variables::replace_substring(&mut new, start, end, &value);
-            } else {
-                Variables::replace_substring(&mut new, start, end, "");
-            }
+        for &(start, end, ref value) in replacements.iter().rev() {
+            Variables::replace_substring(&mut new, start, end, value);
         }
         new.clone()
     }
+
+    /// Expands the string like `expand_variables`, but first assigns the default of each
+    /// `${name:=default}` whose variable is unset or empty. Fails with the message of a
+    /// `${name:?message}` whose variable is unset or empty, or if an expansion is malformed.
+    /// With `nounset`, expanding a variable that is unset fails too.
+    pub fn expand_string_checked(&mut self, original: &str) -> Result<String, String> {
+        try!(self.check_parameters(original));
+        let mut unset = None;
+        let expanded = self.substitute_variables(original, &mut unset);
+        match unset {
+            Some(name) if self.nounset => Err(format!("{}: unbound variable", name)),
+            _ => Ok(expanded),
+        }
+    }
+
+    fn check_parameters(&mut self, text: &str) -> Result<(), String> {
+        let mut index = 0;
+        while let Some(offset) = text[index..].find("${") {
+            let start = index + offset;
+            match Variables::find_closing_brace(text, start + 2) {
+                Some(close) => {
+                    if !text[..start].ends_with('\\') {
+                        try!(self.check_parameter(&text[start+2..close]));
+                    }
+                    index = close + 1;
+                },
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn check_parameter(&mut self, body: &str) -> Result<(), String> {
+        let (name, operator) = Variables::split_parameter(body);
+        if operator.starts_with(":=") || operator.starts_with('=') {
+            let word = operator.trim_left_matches(':')[1..].to_string();
+            let colon = operator.starts_with(':');
+            let is_unset = match self.get_var(name) {
+                Some(ref value) => colon && value.is_empty(),
+                None => true,
+            };
+            if is_unset {
+                if !Variables::is_valid_variable_name(name) || name.is_empty() || name.parse::<usize>().is_ok() {
+                    return Err(format!("{}: cannot assign in this way", name));
+                }
+                let value = try!(self.expand_string_checked(&word));
+                // An empty value would remove the variable again
+                self.variables.insert(name.to_string(), value);
+            }
+        }
+        self.expand_parameter(body).map(|_| ())
+    }
+
+    /// Returns the index of the `}` closing a `${` whose body begins at `start`, skipping over
+    /// nested braces.
+    pub fn find_closing_brace(text: &str, start: usize) -> Option<usize> {
+        let mut depth = 0;
+        for (index, character) in text[start..].char_indices() {
+            match character {
+                '{' => depth += 1,
+                '}' if depth == 0 => return Some(start + index),
+                '}' => depth -= 1,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// Splits the body of a `${...}` into the name of the parameter and the operator after it.
+    fn split_parameter(body: &str) -> (&str, &str) {
+        let length = match body.chars().next() {
+            Some(special) if special == '#' || special == '@' || special.is_digit(10) => 1,
+            _ => body.chars().take_while(|&c| Variables::is_valid_variable_character(c)).map(|c| c.len_utf8()).sum(),
+        };
+        (&body[..length], &body[length..])
+    }
+
+    /// Evaluates the body of a `${...}`, which is the name of a parameter optionally followed by
+    /// an operator, or `#` followed by a name for the length of the parameter.
+    fn expand_parameter(&self, body: &str) -> Result<String, String> {
+        if body.len() > 1 && body.starts_with('#') {
+            let name = &body[1..];
+            if let Some(array) = self.arrays.get(name) {
+                return Ok(array.len().to_string());
+            } else if Variables::split_parameter(name).1.is_empty() {
+                return Ok(self.get_var(name).map_or(0, |value| value.chars().count()).to_string());
+            }
+        }
+        let (name, operator) = Variables::split_parameter(body);
+        if name.is_empty() {
+            return Err(format!("${{{}}}: bad substitution", body));
+        }
+        let value = self.get_var(name);
+        if operator.is_empty() {
+            return Ok(value.unwrap_or(String::new()));
+        }
+
+        // `:-`, `:=`, `:?` and `:+` treat an empty variable like an unset one, `-` and the others
+        // only an unset one
+        let (colon, default_operator) = if operator.starts_with(':') { (true, &operator[1..]) } else { (false, operator) };
+        let kind = match default_operator.chars().next() {
+            Some(kind) if "-=?+".contains(kind) => Some(kind),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            let word = &default_operator[1..];
+            let is_set = match value {
+                Some(ref value) => !colon || !value.is_empty(),
+                None => false,
+            };
+            return match (kind, is_set) {
+                ('-', false) | ('=', false) | ('+', true) => Ok(self.expand_string(word)),
+                ('?', false) if word.is_empty() => Err(format!("{}: parameter not set", name)),
+                ('?', false) => Err(format!("{}: {}", name, self.expand_string(word))),
+                ('+', false) => Ok(String::new()),
+                _ => Ok(value.unwrap_or(String::new())),
+            };
+        }
+
+        let value = value.unwrap_or(String::new());
+        let (pattern, longest) = if operator.starts_with("##") || operator.starts_with("%%") || operator.starts_with("//") {
+            (&operator[2..], true)
+        } else if !colon && (operator.starts_with('#') || operator.starts_with('%') || operator.starts_with('/')) {
+            (&operator[1..], false)
+        } else {
+            return Err(format!("${{{}}}: bad substitution", body));
+        };
+        match operator.chars().next() {
+            Some('#') => Ok(Variables::strip_prefix(&value, &Variables::pattern(&self.expand_string(pattern)), longest)),
+            Some('%') => Ok(Variables::strip_suffix(&value, &Variables::pattern(&self.expand_string(pattern)), longest)),
+            _ => {
+                let (pattern, replacement) = match pattern.find('/') {
+                    Some(slash) => (&pattern[..slash], &pattern[slash+1..]),
+                    None => (pattern, ""),
+                };
+                let pattern = Variables::pattern(&self.expand_string(pattern));
+                Ok(Variables::substitute(&value, &pattern, &self.expand_string(replacement), longest))
+            },
+        }
+    }
+
+    /// Compiles a glob pattern, matching it literally if it is not a valid glob.
+    fn pattern(pattern: &str) -> Pattern {
+        Pattern::new(pattern).unwrap_or_else(|_| Pattern::new(&Pattern::escape(pattern)).unwrap())
+    }
+
+    /// The byte indices of every character boundary in the string, including its end.
+    fn boundaries(value: &str) -> Vec<usize> {
+        value.char_indices().map(|(index, _)| index).chain(Some(value.len())).collect()
+    }
+
+    /// Removes the shortest, or longest, prefix matching the pattern.
+    fn strip_prefix(value: &str, pattern: &Pattern, longest: bool) -> String {
+        let mut ends = Variables::boundaries(value);
+        if longest {
+            ends.reverse();
+        }
+        match ends.into_iter().find(|&end| pattern.matches(&value[..end])) {
+            Some(end) => value[end..].to_string(),
+            None => value.to_string(),
+        }
+    }
+
+    /// Removes the shortest, or longest, suffix matching the pattern.
+    fn strip_suffix(value: &str, pattern: &Pattern, longest: bool) -> String {
+        let mut starts = Variables::boundaries(value);
+        if !longest {
+            starts.reverse();
+        }
+        match starts.into_iter().find(|&start| pattern.matches(&value[start..])) {
+            Some(start) => value[..start].to_string(),
+            None => value.to_string(),
+        }
+    }
+
+    /// Replaces the first, or every, longest match of the pattern.
+    fn substitute(value: &str, pattern: &Pattern, replacement: &str, all: bool) -> String {
+        let boundaries = Variables::boundaries(value);
+        let mut result = String::new();
+        let mut copied = 0;
+        let mut position = 0;
+        while position < boundaries.len() {
+            let start = boundaries[position];
+            if start < copied {
+                position += 1;
+                continue;
+            }
+            let end = boundaries[position + 1..].iter().rev().find(|&&end| pattern.matches(&value[start..end]));
+            match end {
+                Some(&end) => {
+                    result.push_str(&value[copied..start]);
+                    result.push_str(replacement);
+                    copied = end;
+                    if !all {
+                        break;
+                    }
+                },
+                None => (),
+            }
+            position += 1;
+        }
+        result.push_str(&value[copied..]);
+        result
+    }
+
+    /// Returns the elements selected by an index like `1`, or a range like `1..3`, `1..` or `..3`
+    /// that excludes its end. Indices past the end of the array select nothing.
+    fn select<'a>(array: &'a [String], selector: &str) -> Option<&'a [String]> {
+        match selector.find("..") {
+            Some(dots) => {
+                let start = match &selector[..dots] {
+                    "" => 0,
+                    start => match start.parse::<usize>() {
+                        Ok(start) => start,
+                        Err(_) => return None,
+                    },
+                };
+                let end = match &selector[dots+2..] {
+                    "" => array.len(),
+                    end => match end.parse::<usize>() {
+                        Ok(end) => end,
+                        Err(_) => return None,
+                    },
+                };
+                let end = cmp::min(end, array.len());
+                Some(&array[cmp::min(start, end)..end])
+            },
+            None => {
+                selector.parse::<usize>().ok().map(|index| {
+                    if index < array.len() { &array[index..index+1] } else { &array[0..0] }
+                })
+            }
+        }
+    }
+
+    /// Returns the elements of the array that a word like `@array` or `@array[1..]` splats into
+    /// separate arguments, or `None` if the word does not name an array.
+    pub fn splat(&self, word: &str) -> Option<Vec<String>> {
+        if !word.starts_with('@') {
+            return None;
+        }
+        let name: String = word[1..].chars().take_while(|&c| Variables::is_valid_variable_character(c)).collect();
+        let array = match self.arrays.get(&name) {
+            Some(array) => array,
+            None => return None,
+        };
+        let rest = &word[1 + name.len()..];
+        if rest.is_empty() {
+            Some(array.clone())
+        } else if rest.starts_with('[') && rest.ends_with(']') {
+            let selector = self.expand_string(&rest[1..rest.len()-1]);
+            Variables::select(array, &selector).map(|elements| elements.to_vec())
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(test)]
@@ -274,11 +667,100 @@ mod tests {
         assert_eq!("variables: BAR Y", &expanded);
     }
 
+    #[test]
+    fn expand_positional_arguments() {
+        let mut variables = Variables::default();
+        variables.set_args(vec!["script.ion".to_string(), "foo".to_string(), "bar".to_string()]);
+        assert_eq!("script.ion foo bar2 ", &variables.expand_string("$0 $1 $22 $3"));
+        assert_eq!("2: foo bar", &variables.expand_string("$#: $@"));
+        assert_eq!("bar.txt", &variables.expand_string("$2.txt"));
+    }
+
+    #[test]
+    fn let_and_expand_an_array() {
+        let mut variables = Variables::default();
+        assert_eq!(SUCCESS, variables.let_(vec!["let", "files", "=", "[a", "b c", "d]"]));
+        assert_eq!(Some(&vec!["a".to_string(), "b c".to_string(), "d".to_string()]), variables.get_array("files"));
+        variables.set_var("i", "1");
+        assert_eq!("a b c d, b c, b c d, a, 3", &variables.expand_string("$files, $files[$i], $files[1..], $files[..1], $#files"));
+        assert_eq!("[x], ", &variables.expand_string("[x], $files[9]"));
+        variables.let_(vec!["let", "empty=[]"]);
+        assert_eq!(Some(&vec![]), variables.get_array("empty"));
+    }
+
+    #[test]
+    fn splat_an_array() {
+        let mut variables = Variables::default();
+        variables.set_array("files", vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        assert_eq!(Some(vec!["a".to_string(), "b".to_string(), "c".to_string()]), variables.splat("@files"));
+        assert_eq!(Some(vec!["b".to_string()]), variables.splat("@files[1..2]"));
+        assert_eq!(None, variables.splat("@other"));
+        assert_eq!(None, variables.splat("user@files"));
+    }
+
+    #[test]
+    fn braced_parameters() {
+        let mut variables = Variables::default();
+        variables.set_var("FILE", "archive.tar.gz");
+        variables.set_var("EMPTY", "");
+        assert_eq!("archive.tar.gz.bak", &variables.expand_string("${FILE}.bak"));
+        assert_eq!("14", &variables.expand_string("${#FILE}"));
+        assert_eq!("default archive.tar.gz", &variables.expand_string("${EMPTY:-default} ${FILE:-default}"));
+        assert_eq!("other ", &variables.expand_string("${FILE:+other} ${EMPTY:+other}"));
+        assert_eq!("archive.tar.gz", &variables.expand_string("${UNSET:-$FILE}"));
+        assert_eq!("", &variables.expand_string("${FILE!}"));
+    }
+
+    #[test]
+    fn strip_and_substitute_patterns() {
+        let mut variables = Variables::default();
+        variables.set_var("FILE", "src/archive.tar.gz");
+        assert_eq!("archive.tar.gz gz", &variables.expand_string("${FILE#*/} ${FILE##*.}"));
+        assert_eq!("src/archive.tar src/archive", &variables.expand_string("${FILE%.*} ${FILE%%.*}"));
+        assert_eq!("src/archive-tar.gz src/archive-tar-gz", &variables.expand_string("${FILE/./-} ${FILE//./-}"));
+        assert_eq!("src/.tar.gz", &variables.expand_string("${FILE/a*e/}"));
+    }
+
+    #[test]
+    fn assign_default_and_require_parameters() {
+        let mut variables = Variables::default();
+        assert_eq!(Ok("fallback".to_string()), variables.expand_string_checked("${NAME:=fallback}"));
+        assert_eq!(Some("fallback".to_string()), variables.get_var("NAME"));
+        assert_eq!(Err("MISSING: parameter not set".to_string()), variables.expand_string_checked("${MISSING:?}"));
+        assert_eq!(Err("MISSING: give a name".to_string()), variables.expand_string_checked("${MISSING:?give a name}"));
+        assert_eq!(Err("${FILE!}: bad substitution".to_string()), variables.expand_string_checked("${FILE!}"));
+        assert_eq!(Ok("fallback".to_string()), variables.expand_string_checked("${NAME:?}"));
+    }
+
+    #[test]
+    fn unset_variables_with_nounset() {
+        let mut variables = Variables::default();
+        variables.set_var("FILE", "archive.tar.gz");
+        assert_eq!(Ok(" archive.tar.gz".to_string()), variables.expand_string_checked("$UNSET ${FILE}"));
+        variables.nounset = true;
+        assert_eq!(Err("UNSET: unbound variable".to_string()), variables.expand_string_checked("$FILE $UNSET"));
+        assert_eq!(Err("UNSET: unbound variable".to_string()), variables.expand_string_checked("${UNSET}"));
+        assert_eq!(Err("1: unbound variable".to_string()), variables.expand_string_checked("$1"));
+        assert_eq!(Ok("default $".to_string()), variables.expand_string_checked("${UNSET:-default} $"));
+    }
+
     #[test]
     fn replace_substring() {
         let mut string = "variable: $FOO".to_owned();
         Variables::replace_substring(&mut string, 10, 13, "BAR");
         assert_eq!("variable: BAR", string);
+        let mut string = "é: $FOO".to_owned();
+        Variables::replace_substring(&mut string, 4, 7, "BAR");
+        assert_eq!("é: BAR", string);
+    }
+
+    #[test]
+    fn expand_after_non_ascii_text() {
+        let mut variables = Variables::default();
+        variables.set_var("FOO", "bar");
+        assert_eq!("ébar", &variables.expand_string("é$FOO"));
+        assert_eq!("éy ünï bar", &variables.expand_string("é${X:-y} ünï $FOO"));
+        assert_eq!("é\\$FOO", &variables.expand_string("é\\$FOO"));
     }
 
     #[test]